@@ -1,9 +1,12 @@
-use heimdall::{Result, Tree};
+use heimdall::{Error, Explanation, ProgressCallback, Result, Tree, TreeOptions};
 use std::{
-    ffi::CString,
-    io::{BufReader, Read},
-    os::unix::ffi::OsStrExt,
-    path::PathBuf,
+    env,
+    ffi::OsString,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
 use structopt::StructOpt;
@@ -17,13 +20,212 @@ struct Arguments {
     /// The root directory to watch (defaults to the current working directory)
     #[structopt(default_value = ".", long = "root")]
     root: PathBuf,
+    /// Match the patterns in ignore files regardless of case
+    #[structopt(long = "ignore-case")]
+    ignore_case: bool,
+    /// Only track the paths that match this glob, along with the directories leading to them. Can
+    /// be given more than once
+    #[structopt(long = "include", parse(from_os_str), number_of_values = 1)]
+    include: Vec<OsString>,
+    /// The most files to keep open at once. Directories beyond this are skipped
+    #[structopt(long = "max-open-files")]
+    max_open_files: Option<usize>,
+    /// The number of threads to crawl the tree with. Defaults to one for each CPU
+    #[structopt(long = "threads", default_value = "0", hide_default_value = true)]
+    threads: usize,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Print the paths that are left out of the tree by its ignore files, like git check-ignore.
+    /// Exits with status 1 if none of the paths are ignored.
+    CheckIgnore {
+        /// Show the ignore file, line number and pattern that decided each path, in the form
+        /// <source>:<line>:<pattern><TAB><path>. Hidden paths aren't decided by any pattern, so
+        /// they're shown as ::<TAB><path>, the way git shows paths that no pattern matched
+        #[structopt(short = "v", long = "verbose")]
+        verbose: bool,
+        /// The paths to check
+        #[structopt(required = true)]
+        paths: Vec<PathBuf>,
+    },
+}
+
+/// Turn a path from the command line into an absolute path, resolving any symlinks in its parent
+/// directories (but not the path itself) so that it lines up with the canonical root of the tree.
+fn absolute(path: &Path) -> Result<PathBuf> {
+    let path = env::current_dir()?.join(path);
+    let resolved = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => parent.canonicalize().ok().map(|parent| parent.join(name)),
+        _ => None,
+    };
+
+    Ok(resolved.unwrap_or(path))
+}
+
+/// Shorten a path for display, by making it relative to the current directory where possible.
+fn display_path(path: &Path) -> PathBuf {
+    env::current_dir()
+        .ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok().map(Path::to_path_buf))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// How often the progress line is redrawn.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Show how far the crawl has got on a single line of stderr, if stderr is a terminal.
+fn progress_line() -> Option<ProgressCallback> {
+    if !io::stderr().is_terminal() {
+        return None;
+    }
+
+    let last_drawn = Mutex::new(None::<Instant>);
+    Some(ProgressCallback::new(move |progress| {
+        let mut last_drawn = last_drawn.lock().unwrap();
+        if last_drawn.is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL) {
+            return;
+        }
+        *last_drawn = Some(Instant::now());
+        eprint!(
+            "\r\x1b[Kindexing: {} directories, {} files ({} ignored) {}",
+            progress.dirs_scanned,
+            progress.files_seen,
+            progress.ignored,
+            display_path(&progress.current).display()
+        );
+    }))
+}
+
+/// Print a summary of the paths that couldn't be added to the tree to stderr.
+fn report_errors(tree: &Tree) {
+    let errors = tree.errors();
+    if errors.is_empty() {
+        return;
+    }
+
+    for (path, error) in errors {
+        match error.path() {
+            Some(_) => eprintln!("{}", error),
+            None => eprintln!("{}: {}", display_path(path).display(), error),
+        }
+    }
+    // invalid lines of ignore files are skipped without leaving out any paths
+    let skipped = errors
+        .iter()
+        .filter(|(_, error)| !matches!(error, Error::InIgnoreFile(..)))
+        .count();
+    if skipped > 0 {
+        eprintln!(
+            "hd: skipped {} path{} that could not be read",
+            skipped,
+            if skipped == 1 { "" } else { "s" }
+        );
+    }
+}
+
+/// The line that check-ignore prints for a path, if it prints one.
+fn check_ignore_line(explanation: &Explanation, path: &Path, verbose: bool) -> Option<String> {
+    match explanation {
+        Explanation::Matched { source, .. } if verbose => Some(format!(
+            "{}:{}:{}\t{}",
+            display_path(&source.path).display(),
+            source.line,
+            source.pattern,
+            path.display()
+        )),
+        Explanation::Hidden if verbose => Some(format!("::\t{}", path.display())),
+        explanation if explanation.is_ignored() => Some(path.display().to_string()),
+        _ => None,
+    }
+}
+
+fn check_ignore(tree: &Tree, verbose: bool, paths: Vec<PathBuf>) -> Result<bool> {
+    let mut any_ignored = false;
+
+    for path in paths {
+        let explanation = tree.explain_ignore(absolute(&path)?);
+        any_ignored |= explanation.is_ignored();
+
+        if let Some(line) = check_ignore_line(&explanation, &path, verbose) {
+            println!("{}", line);
+        }
+    }
+
+    Ok(any_ignored)
 }
 
 fn main() -> Result<()> {
     let args = Arguments::from_args();
     env_logger::init();
 
-    let tree = Tree::new(args.root)?;
-    println!("{:?}", tree);
+    let options = TreeOptions {
+        ignore_case: args.ignore_case,
+        include: args.include,
+        max_open_files: args.max_open_files,
+        threads: args.threads,
+        progress: progress_line(),
+        ..TreeOptions::default()
+    };
+    let show_progress = options.progress.is_some();
+    let tree = Tree::with_options(args.root, options);
+    if show_progress {
+        eprint!("\r\x1b[K");
+    }
+    let tree = tree?;
+    report_errors(&tree);
+
+    match args.command {
+        None => println!("{:?}", tree),
+        Some(Command::CheckIgnore { verbose, paths }) => {
+            if !check_ignore(&tree, verbose, paths)? {
+                process::exit(1);
+            }
+        }
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::check_ignore_line;
+    use heimdall::{Explanation, IgnoreSource};
+    use std::path::Path;
+
+    #[test]
+    fn check_ignore_lines() {
+        let path = Path::new("target/main.o");
+        let matched = |negated| Explanation::Matched {
+            source: IgnoreSource {
+                path: "/nonexistent/.gitignore".into(),
+                line: 3,
+                pattern: if negated { "!*.o" } else { "*.o" }.into(),
+            },
+            negated,
+        };
+
+        let line = |explanation, verbose| check_ignore_line(&explanation, path, verbose);
+        assert_eq!(
+            Some("/nonexistent/.gitignore:3:*.o\ttarget/main.o".into()),
+            line(matched(false), true)
+        );
+        assert_eq!(Some("target/main.o".into()), line(matched(false), false));
+        assert_eq!(
+            Some("/nonexistent/.gitignore:3:!*.o\ttarget/main.o".into()),
+            line(matched(true), true)
+        );
+        assert_eq!(None, line(matched(true), false));
+        assert_eq!(
+            Some("::\ttarget/main.o".into()),
+            line(Explanation::Hidden, true)
+        );
+        assert_eq!(
+            Some("target/main.o".into()),
+            line(Explanation::Hidden, false)
+        );
+        assert_eq!(None, line(Explanation::Included, true));
+    }
+}
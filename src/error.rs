@@ -1,29 +1,149 @@
 use crate::{fs::FileType, glob::TokenSet};
-use std::ptr::NonNull;
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    ptr::NonNull,
+};
 
 use errno::Errno;
 use thiserror::Error;
 
+/// A filesystem operation that can fail.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Operation {
+    Open,
+    Stat,
+    ReadLink,
+    Scan,
+    Read,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Operation::Open => "open",
+            Operation::Stat => "stat",
+            Operation::ReadLink => "read the link",
+            Operation::Scan => "scan",
+            Operation::Read => "read",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum Error {
     #[error("IO failure {0}")]
-    IoError(#[from] std::io::Error),
+    IoError(#[from] io::Error),
+    #[error("Failed to {operation} {} - {source}", .path.display())]
+    Fs {
+        operation: Operation,
+        path: PathBuf,
+        source: io::Error,
+    },
+    #[error(
+        "Ran out of file descriptors - the limit of {0} open files can't be raised any further"
+    )]
+    OutOfFiles(u64),
+    #[error("Ran out of file descriptors - the tree's budget of {0} open files has been used up")]
+    OverBudget(usize),
+    #[error("The crawl was cancelled")]
+    Cancelled,
+    #[error("File {1} on device {0} conflicts with a different entry already in the tree")]
+    ConflictingEntry(u64, u64),
+    #[error("The tree can't hold any more than its {0} entries")]
+    StoreFull(usize),
     #[error("Invalid file type {0}")]
     InvalidFileType(u8),
     #[error("Unsupported file type {0:?}")]
     UnsupportedFileType(FileType),
-    #[error("Failed to parse glob ({0:?}) at index {2} - expected {1:?}")]
+    #[error("Failed to parse glob - expected {1}\n{}", underline(.0, *.2))]
     InvalidGlobParse(String, TokenSet, usize),
     #[error("Failed to parse glob ({0:?}) - {1}")]
     InvalidGlobCompile(String, &'static str),
+    #[error("{}:{1}: {2}", .0.display())]
+    InIgnoreFile(PathBuf, usize, Box<Error>),
+}
+
+/// Show a glob, with a caret underneath the character at the given byte index.
+fn underline(glob: &str, index: usize) -> String {
+    let column = glob
+        .get(..index)
+        .map_or(index, |start| start.chars().count());
+    format!("    {}\n    {:>width$}", glob, "^", width = column + 1)
 }
 
 impl Error {
+    /// The path that the error happened at, if it is known.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::Fs { path, .. } | Error::InIgnoreFile(path, _, _) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The filesystem operation that failed, if the error came from one.
+    pub fn operation(&self) -> Option<Operation> {
+        match self {
+            Error::Fs { operation, .. } => Some(*operation),
+            _ => None,
+        }
+    }
+
+    /// The underlying IO error, if there is one.
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            Error::IoError(error) | Error::Fs { source: error, .. } => Some(error),
+            Error::InIgnoreFile(_, _, error) => error.io_error(),
+            _ => None,
+        }
+    }
+
+    /// Attach the operation that failed, and the path that it failed on, to an IO error. The
+    /// path may be relative, in which case in_dir can be used to complete it.
+    pub(crate) fn context(self, operation: Operation, path: impl AsRef<Path>) -> Self {
+        match self {
+            Error::IoError(source) => Error::Fs {
+                operation,
+                path: path.as_ref().to_path_buf(),
+                source,
+            },
+            other => other,
+        }
+    }
+
+    /// Make the path of an error relative to the directory dir. An empty path refers to dir
+    /// itself.
+    pub(crate) fn in_dir(self, dir: impl AsRef<Path>) -> Self {
+        match self {
+            Error::Fs {
+                operation,
+                path,
+                source,
+            } => {
+                let dir = dir.as_ref();
+                let path = if path.as_os_str().is_empty() {
+                    dir.to_path_buf()
+                } else {
+                    dir.join(path)
+                };
+                Error::Fs {
+                    operation,
+                    path,
+                    source,
+                }
+            }
+            other => other,
+        }
+    }
+
     /// Takes the integer returned from a C function (-1 is an error, other values are treated as
     /// success) and converts it to a result.
     pub fn from_int(result: i32) -> Result<i32> {
         if result == -1 {
-            let error = std::io::Error::last_os_error();
+            let error = io::Error::last_os_error();
             Err(error.into())
         } else {
             Ok(result)
@@ -34,7 +154,7 @@ impl Error {
     /// success) and converts it to a result.
     pub fn from_size(result: isize) -> Result<isize> {
         if result == -1 {
-            let error = std::io::Error::last_os_error();
+            let error = io::Error::last_os_error();
             Err(error.into())
         } else {
             Ok(result)
@@ -47,7 +167,7 @@ impl Error {
         match NonNull::new(result) {
             Some(ptr) => Ok(ptr),
             None => {
-                let error = std::io::Error::last_os_error();
+                let error = io::Error::last_os_error();
                 Err(error.into())
             }
         }
@@ -67,7 +187,7 @@ impl Error {
         match errno::errno() {
             Errno(0) => Ok(output),
             Errno(e) => {
-                let error = std::io::Error::from_raw_os_error(e);
+                let error = io::Error::from_raw_os_error(e);
                 Err(error.into())
             }
         }
@@ -1,19 +1,29 @@
-use crate::error::{Error, Result};
+use crate::error::{Error, Operation, Result};
 use std::{
-    convert::TryFrom,
-    ffi::{CStr, CString},
+    convert::{TryFrom, TryInto},
+    ffi::{CStr, CString, OsStr},
     io::Read,
     mem::MaybeUninit,
     os::{
         raw::{c_char, c_int, c_void},
-        unix::io::{AsRawFd, RawFd},
+        unix::{
+            ffi::OsStrExt,
+            io::{AsRawFd, FromRawFd, RawFd},
+        },
     },
-    ptr::NonNull,
+    path::Path,
 };
 
-#[derive(Debug, PartialEq, Eq)]
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+
+/// View a C string as a path, for use in error messages.
+fn as_path(path: &CStr) -> &Path {
+    Path::new(OsStr::from_bytes(path.to_bytes()))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum FileType {
-    Unknown,
     Fifo,
     Character,
     Directory,
@@ -21,25 +31,78 @@ pub enum FileType {
     Regular,
     Link,
     Socket,
+    #[cfg(target_os = "macos")]
     Whiteout,
 }
 
-impl TryFrom<u16> for FileType {
+/// The file type bits of st_mode for a whiteout, which libc doesn't have.
+#[cfg(target_os = "macos")]
+const S_IFWHT: libc::mode_t = 0o160000;
+
+impl TryFrom<libc::mode_t> for FileType {
     type Error = Error;
 
-    fn try_from(other: u16) -> Result<Self> {
-        match other.to_be_bytes()[0] >> 4 {
-            libc::DT_UNKNOWN => Ok(FileType::Unknown),
-            libc::DT_FIFO => Ok(FileType::Fifo),
-            libc::DT_CHR => Ok(FileType::Character),
-            libc::DT_DIR => Ok(FileType::Directory),
-            libc::DT_BLK => Ok(FileType::Block),
-            libc::DT_REG => Ok(FileType::Regular),
-            libc::DT_LNK => Ok(FileType::Link),
-            libc::DT_SOCK => Ok(FileType::Socket),
-            // libc doesn't have the macos specific things
-            14 => Ok(FileType::Whiteout),
-            mystery => Err(Error::InvalidFileType(mystery)),
+    /// Decode the file type from the st_mode field of a stat buffer.
+    fn try_from(mode: libc::mode_t) -> Result<Self> {
+        match mode & libc::S_IFMT {
+            libc::S_IFIFO => Ok(FileType::Fifo),
+            libc::S_IFCHR => Ok(FileType::Character),
+            libc::S_IFDIR => Ok(FileType::Directory),
+            libc::S_IFBLK => Ok(FileType::Block),
+            libc::S_IFREG => Ok(FileType::Regular),
+            libc::S_IFLNK => Ok(FileType::Link),
+            libc::S_IFSOCK => Ok(FileType::Socket),
+            #[cfg(target_os = "macos")]
+            S_IFWHT => Ok(FileType::Whiteout),
+            mystery => Err(Error::InvalidFileType((mystery >> 12) as u8)),
+        }
+    }
+}
+
+impl FileType {
+    /// Decode the file type from the d_type field of a directory entry. Returns None if the
+    /// directory didn't say what type the entry is.
+    fn from_dirent(d_type: u8) -> Option<Self> {
+        match d_type {
+            libc::DT_FIFO => Some(FileType::Fifo),
+            libc::DT_CHR => Some(FileType::Character),
+            libc::DT_DIR => Some(FileType::Directory),
+            libc::DT_BLK => Some(FileType::Block),
+            libc::DT_REG => Some(FileType::Regular),
+            libc::DT_LNK => Some(FileType::Link),
+            libc::DT_SOCK => Some(FileType::Socket),
+            #[cfg(target_os = "macos")]
+            libc::DT_WHT => Some(FileType::Whiteout),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of a directory, as listed by the directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: CString,
+    /// The type of the entry, if the filesystem includes it in directory listings.
+    pub file_type: Option<FileType>,
+    /// The inode of the entry. For a mount point, this is the inode of the directory that was
+    /// mounted over, not the root of the mounted filesystem.
+    pub inode: u64,
+}
+
+/// Identifies a file on the system: the device that it's on, and its inode on that device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct FileId {
+    pub dev: u64,
+    pub inode: u64,
+}
+
+impl FileId {
+    // st_dev is signed on macOS
+    #[allow(clippy::unnecessary_cast)]
+    fn from_stat(buf: &libc::stat) -> Self {
+        FileId {
+            dev: buf.st_dev as u64,
+            inode: buf.st_ino,
         }
     }
 }
@@ -51,75 +114,127 @@ pub struct File {
 }
 
 impl File {
-    /// Flag for open that lets you open symlinks as if they're real files.
+    /// Flags for opening a file that can be read from, without following it if it is a symlink.
+    /// Opening a FIFO doesn't wait for a writer, and reading the file doesn't update its access
+    /// time where that's permitted.
+    #[cfg(target_os = "macos")]
+    const READ_FLAGS: c_int =
+        libc::O_RDONLY | libc::O_NONBLOCK | libc::O_CLOEXEC | libc::O_NOFOLLOW;
+    #[cfg(not(target_os = "macos"))]
+    const READ_FLAGS: c_int =
+        libc::O_RDONLY | libc::O_NONBLOCK | libc::O_CLOEXEC | libc::O_NOFOLLOW | Self::O_NOATIME;
+
+    /// Flags for opening a file without reading from it: a symlink itself rather than the file it
+    /// links to, or a FIFO, socket or device. On Linux, this gives a path-only fd, which can't be
+    /// read from but doesn't touch the file itself.
+    #[cfg(target_os = "macos")]
+    const PATH_FLAGS: c_int =
+        libc::O_RDONLY | libc::O_NONBLOCK | libc::O_NOCTTY | libc::O_CLOEXEC | Self::O_SYMLINK;
+    #[cfg(not(target_os = "macos"))]
+    const PATH_FLAGS: c_int = libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+
+    /// Flag for open that lets you open symlinks as if they're real files (libc doesn't have it).
+    #[cfg(target_os = "macos")]
     const O_SYMLINK: c_int = 0x200000;
 
+    /// Flag for open that stops reads from updating the access time. It's only permitted for files
+    /// owned by the caller, otherwise the open fails with EPERM and is retried without it.
+    #[cfg(target_os = "linux")]
+    const O_NOATIME: c_int = libc::O_NOATIME;
+    #[cfg(not(target_os = "linux"))]
+    const O_NOATIME: c_int = 0;
+
+    /// macOS refuses a soft limit above OPEN_MAX, even when the hard limit is unlimited.
+    #[cfg(target_os = "macos")]
+    const OPEN_MAX: libc::rlim_t = 10240;
+
+    /// Raise the soft limit on open files to the hard limit. Fails if it's already there, since
+    /// then there's no way to open any more files.
     fn increase_ulimits() -> Result<()> {
         let mut limit = MaybeUninit::uninit();
         let mut limit = unsafe {
             Error::from_int(libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()))?;
             limit.assume_init()
         };
-        println!(
-            "Changing limit from {} to {}",
+        #[cfg(target_os = "macos")]
+        let hard_limit = limit.rlim_max.min(Self::OPEN_MAX);
+        #[cfg(not(target_os = "macos"))]
+        let hard_limit = limit.rlim_max;
+
+        if limit.rlim_cur >= hard_limit {
+            return Err(Error::OutOfFiles(limit.rlim_cur as u64));
+        }
+        log::info!(
+            "Raising the limit on open files from {} to {}",
             limit.rlim_cur,
-            limit.rlim_cur * 2
+            hard_limit
         );
-        limit.rlim_cur *= 2;
+        limit.rlim_cur = hard_limit;
         Error::from_int(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) })?;
 
         Ok(())
     }
 
-    fn open_raw(path: &CStr) -> Result<Self> {
-        let fd = Error::from_int(unsafe {
-            libc::open(path.as_ptr(), libc::O_RDONLY | Self::O_SYMLINK)
-        })?;
-        Ok(File { fd })
+    /// Run an open call, raising the limit on open files and trying again if it has been reached,
+    /// and dropping O_NOATIME if it isn't permitted for this file.
+    fn open_with(flags: c_int, open: impl Fn(c_int) -> c_int) -> Result<Self> {
+        let mut flags = flags;
+        loop {
+            match Error::from_int(open(flags)) {
+                Ok(fd) => return Ok(File { fd }),
+                Err(Error::IoError(error)) => match error.raw_os_error() {
+                    Some(libc::EMFILE) => Self::increase_ulimits()?,
+                    Some(libc::EPERM) if flags & Self::O_NOATIME != 0 => flags &= !Self::O_NOATIME,
+                    _ => return Err(Error::IoError(error)),
+                },
+                Err(error) => return Err(error),
+            }
+        }
     }
 
     /// Open a new file, using a path that's relative to the current directory.
     pub fn open(path: &CStr) -> Result<Self> {
-        match Self::open_raw(path) {
-            Err(Error::IoError(error)) if error.raw_os_error() == Some(libc::EMFILE) => {
-                Self::increase_ulimits()?;
-                Self::open_raw(path)
-            }
-            regular => regular,
-        }
+        Self::open_with(Self::READ_FLAGS, |flags| unsafe {
+            libc::open(path.as_ptr(), flags)
+        })
+        .map_err(|error| error.context(Operation::Open, as_path(path)))
     }
 
-    fn open_at_raw(&self, path: &CStr) -> Result<Self> {
-        let fd = Error::from_int(unsafe {
-            libc::openat(self.fd, path.as_ptr(), libc::O_RDONLY | Self::O_SYMLINK)
-        })?;
-        Ok(File { fd })
+    fn open_at_with(&self, path: &CStr, flags: c_int) -> Result<Self> {
+        Self::open_with(flags, |flags| unsafe {
+            libc::openat(self.fd, path.as_ptr(), flags)
+        })
+        .map_err(|error| error.context(Operation::Open, as_path(path)))
     }
 
-    /// Open a new file that is a child of this file (assuming this file is a directory).
+    /// Open a new file that is a child of this file (assuming this file is a directory), so that
+    /// it can be read from. Fails if the child is a symlink - see open_path_at. Errors are given a
+    /// path relative to this file.
     pub fn open_at(&self, path: &CStr) -> Result<Self> {
-        match self.open_at_raw(path) {
-            Err(Error::IoError(error)) if error.raw_os_error() == Some(libc::EMFILE) => {
-                Self::increase_ulimits()?;
-                self.open_at(path)
-            }
-            regular => regular,
-        }
+        self.open_at_with(path, Self::READ_FLAGS)
     }
 
-    /// Gets some metadata (file type and inode number) from this file.
-    pub fn stat(&self) -> Result<(FileType, u64)> {
+    /// Open a child of this file that won't be read from, such as a symlink (which isn't followed)
+    /// or a special file. This can't block, and on Linux it has no effect on the file itself.
+    pub fn open_path_at(&self, path: &CStr) -> Result<Self> {
+        self.open_at_with(path, Self::PATH_FLAGS)
+    }
+
+    /// Gets some metadata (file type, and the device and inode numbers) from this file. Errors are
+    /// given an empty path, which refers to this file.
+    pub fn stat(&self) -> Result<(FileType, FileId)> {
         let mut buf = MaybeUninit::uninit();
-        Error::from_int(unsafe { libc::fstat(self.fd, buf.as_mut_ptr()) })?;
+        Error::from_int(unsafe { libc::fstat(self.fd, buf.as_mut_ptr()) })
+            .map_err(|error| error.context(Operation::Stat, ""))?;
         let buf = unsafe { buf.assume_init() };
         let file_type = FileType::try_from(buf.st_mode)?;
-        let inode = buf.st_ino;
 
-        Ok((file_type, inode))
+        Ok((file_type, FileId::from_stat(&buf)))
     }
 
-    /// Gets some metadata (file type and inode number) form a child of this file.
-    pub fn stat_at(&self, path: &CStr) -> Result<(FileType, u64)> {
+    /// Gets some metadata (file type, and the device and inode numbers) from a child of this file.
+    /// Errors are given a path relative to this file.
+    pub fn stat_at(&self, path: &CStr) -> Result<(FileType, FileId)> {
         let mut buf = MaybeUninit::uninit();
         Error::from_int(unsafe {
             libc::fstatat(
@@ -128,54 +243,132 @@ impl File {
                 buf.as_mut_ptr(),
                 libc::AT_SYMLINK_NOFOLLOW,
             )
-        })?;
+        })
+        .map_err(|error| error.context(Operation::Stat, as_path(path)))?;
         let buf = unsafe { buf.assume_init() };
         let file_type = FileType::try_from(buf.st_mode)?;
-        let inode = buf.st_ino;
 
-        Ok((file_type, inode))
+        Ok((file_type, FileId::from_stat(&buf)))
+    }
+
+    /// Gets the metadata of several children of this file, like stat_at. With io_uring, they're
+    /// all looked up in one batch.
+    pub fn stat_all_at(&self, names: &[&CStr]) -> Vec<Result<(FileType, FileId)>> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        {
+            if let Some(output) = uring::stat_all_at(self, names) {
+                return output;
+            }
+        }
+        names.iter().map(|name| self.stat_at(name)).collect()
     }
 
-    /// Scan this directory, find the names of all of the child files within this directory. Skips
-    /// .. entries.
-    pub fn scan(&self) -> Result<Vec<CString>> {
-        let fd_clone = Error::from_int(unsafe { libc::dup(self.fd) })?;
+    /// Move back to the start of this file, so that it can be read again from the beginning.
+    pub fn rewind(&mut self) -> Result<()> {
+        match unsafe { libc::lseek(self.fd, 0, libc::SEEK_SET) } {
+            -1 => Err(Error::from(std::io::Error::last_os_error())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Scan this directory, find the names and types of all of the child files within this
+    /// directory. Skips .. entries. Errors are given an empty path, which refers to this directory.
+    pub fn scan(&self) -> Result<Vec<DirEntry>> {
+        self.scan_raw()
+            .map_err(|error| error.context(Operation::Scan, ""))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn scan_raw(&self) -> Result<Vec<DirEntry>> {
+        // the offset may be left over from an earlier scan
+        if unsafe { libc::lseek(self.fd, 0, libc::SEEK_SET) } == -1 {
+            return Err(Error::from(std::io::Error::last_os_error()));
+        }
+
+        let mut buf = vec![0u8; 32 * 1024];
+        let mut output = Vec::new();
+        loop {
+            let length = Error::from_size(unsafe {
+                libc::syscall(
+                    libc::SYS_getdents64,
+                    self.fd,
+                    buf.as_mut_ptr() as *mut c_void,
+                    buf.len(),
+                ) as isize
+            })? as usize;
+            if length == 0 {
+                return Ok(output);
+            }
+
+            // each record is a linux_dirent64: the inode, the offset of the next record, the length
+            // of this record, the type, and then the nul terminated name
+            let mut records = &buf[..length];
+            while !records.is_empty() {
+                let inode = u64::from_ne_bytes(records[0..8].try_into().unwrap());
+                let record_length = u16::from_ne_bytes(records[16..18].try_into().unwrap());
+                let (record, rest) = records.split_at(record_length as usize);
+                records = rest;
+
+                let name = CStr::from_bytes_until_nul(&record[19..])
+                    .expect("Directory entry name is missing its nul byte");
+                if name.to_bytes() == b".." {
+                    continue;
+                }
+                output.push(DirEntry {
+                    name: name.to_owned(),
+                    file_type: FileType::from_dirent(record[18]),
+                    inode,
+                });
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn scan_raw(&self) -> Result<Vec<DirEntry>> {
+        let fd_clone = Error::from_int(unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) })?;
         let dirp = Error::from_ptr(unsafe { libc::fdopendir(fd_clone) })?.as_ptr();
         scopeguard::defer! {
             if let Err(err) = Error::from_int(unsafe { libc::closedir(dirp) }) {
                 log::warn!("Error closing directory {} - {}", fd_clone, err);
             }
         };
+        // the duplicate shares its offset with the original, which may be left over from an earlier
+        // scan
+        unsafe { libc::rewinddir(dirp) };
 
         Error::with_errno(|| {
             let mut output = Vec::new();
-            while let Some(entry) = NonNull::new(unsafe { libc::readdir(dirp) }) {
-                let name = unsafe {
-                    let entry = entry.as_ref();
-                    let name = CStr::from_ptr(entry.d_name[..].as_ptr());
-                    name.to_owned()
-                };
-                if name.as_bytes() == b".." {
+            while let Some(entry) = std::ptr::NonNull::new(unsafe { libc::readdir(dirp) }) {
+                let entry = unsafe { entry.as_ref() };
+                let name = unsafe { CStr::from_ptr(entry.d_name[..].as_ptr()) };
+                if name.to_bytes() == b".." {
                     continue;
                 }
-                output.push(name);
+                output.push(DirEntry {
+                    name: name.to_owned(),
+                    file_type: FileType::from_dirent(entry.d_type),
+                    inode: entry.d_ino,
+                });
             }
             output
         })
     }
 
-    /// Find the file that this symlink links to.
+    /// Find the file that a symlink within this directory links to. Errors are given a path
+    /// relative to this directory.
     pub fn get_link_name(&self, name: &CStr) -> Result<CString> {
-        let mut buf = Vec::with_capacity(1024);
+        let mut buf = Vec::<u8>::with_capacity(libc::PATH_MAX as usize);
         let length = Error::from_size(unsafe {
             libc::readlinkat(
                 self.fd,
                 name.as_ptr(),
                 buf.as_mut_ptr() as *mut c_char,
-                buf.len(),
+                buf.capacity(),
             )
-        })?;
-        buf.truncate(length as usize);
+        })
+        .map_err(|error| error.context(Operation::ReadLink, as_path(name)))?;
+        // readlinkat wrote this many bytes into the buffer
+        unsafe { buf.set_len(length as usize) };
         Ok(CString::new(buf).expect("Nul byte in resolved symlink name"))
     }
 }
@@ -198,6 +391,12 @@ impl AsRawFd for File {
     }
 }
 
+impl FromRawFd for File {
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        File { fd }
+    }
+}
+
 impl Drop for File {
     fn drop(&mut self) {
         if let Err(error) = Error::from_int(unsafe { libc::close(self.fd) }) {
@@ -205,3 +404,87 @@ impl Drop for File {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{File, FileType};
+    use crate::{error::Operation, tree::test::TestDir};
+    use std::{convert::TryFrom, ffi::CString, os::unix::io::AsRawFd};
+
+    #[test]
+    fn file_type_from_mode() {
+        let file_type = |mode| FileType::try_from(mode).unwrap();
+        assert_eq!(FileType::Directory, file_type(libc::S_IFDIR | 0o755));
+        assert_eq!(FileType::Regular, file_type(libc::S_IFREG | 0o4644));
+        assert_eq!(FileType::Link, file_type(libc::S_IFLNK | 0o777));
+        assert_eq!(FileType::Socket, file_type(libc::S_IFSOCK));
+        FileType::try_from(0o644).unwrap_err();
+    }
+
+    #[test]
+    fn close_on_exec() {
+        let dir = File::open(&CString::new(env!("CARGO_MANIFEST_DIR")).unwrap()).unwrap();
+        let files = [
+            dir.open_at(&CString::new("Cargo.toml").unwrap()).unwrap(),
+            dir.open_path_at(&CString::new("src").unwrap()).unwrap(),
+        ];
+        for file in files.iter().chain(Some(&dir)) {
+            let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFD) };
+            assert_eq!(libc::FD_CLOEXEC, flags & libc::FD_CLOEXEC);
+        }
+    }
+
+    #[test]
+    fn scan_types() {
+        let root = TestDir::new("scan", &["dir/", "file"]);
+        std::os::unix::fs::symlink("file", root.join("link")).unwrap();
+
+        let dir = File::open(&CString::new(root.to_str().unwrap()).unwrap()).unwrap();
+        let mut entries = dir.scan().unwrap();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        // a second scan starts from the beginning again
+        assert_eq!(entries.len(), dir.scan().unwrap().len());
+        let inodes = entries
+            .iter()
+            .map(|entry| dir.stat_at(&entry.name).unwrap().1.inode)
+            .collect::<Vec<_>>();
+
+        let names = entries
+            .iter()
+            .map(|entry| entry.name.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![".", "dir", "file", "link"], names);
+        for (entry, inode) in entries.iter().zip(inodes) {
+            assert_eq!(inode, entry.inode);
+        }
+        for (entry, expected) in
+            entries[1..]
+                .iter()
+                .zip(&[FileType::Directory, FileType::Regular, FileType::Link])
+        {
+            // not every filesystem reports types when listing directories
+            assert!(entry
+                .file_type
+                .is_none_or(|file_type| file_type == *expected));
+        }
+    }
+
+    #[test]
+    fn stat_all() {
+        let dir = File::open(&CString::new(env!("CARGO_MANIFEST_DIR")).unwrap()).unwrap();
+        let names = ["Cargo.toml", "src", "missing"]
+            .iter()
+            .map(|name| CString::new(*name).unwrap())
+            .collect::<Vec<_>>();
+        let names = names.iter().map(|name| &**name).collect::<Vec<_>>();
+
+        let stats = dir.stat_all_at(&names);
+        assert_eq!(3, stats.len());
+        for (name, stat) in names.iter().zip(&stats[..2]) {
+            assert_eq!(&dir.stat_at(name).unwrap(), stat.as_ref().unwrap());
+        }
+        let error = stats[2].as_ref().unwrap_err();
+        assert_eq!(Some(Operation::Stat), error.operation());
+        assert_eq!(Some("missing".as_ref()), error.path());
+    }
+}
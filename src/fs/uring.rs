@@ -0,0 +1,135 @@
+//! Batched lookups through io_uring, so that a whole directory can be looked up with a handful of
+//! system calls. Only lookups are batched: opening directories, reading symlinks and reading
+//! ignore files are left as ordinary system calls, since they're only needed for the entries that
+//! end up in the tree, which is decided one entry at a time.
+
+use super::{as_path, File, FileId, FileType};
+use crate::error::{Error, Operation, Result};
+use io_uring::{opcode, types, IoUring, Probe};
+use std::{cell::RefCell, convert::TryFrom, ffi::CStr, io, mem, mem::MaybeUninit};
+
+/// The most lookups that are submitted at once.
+const RING_SIZE: u32 = 256;
+
+thread_local! {
+    // each thread sets up its own ring the first time that it needs one. If io_uring can't be
+    // used, that's remembered so that it isn't tried again.
+    static RING: RefCell<Option<Option<IoUring>>> = const { RefCell::new(None) };
+}
+
+fn setup() -> Option<IoUring> {
+    let ring = IoUring::new(RING_SIZE).and_then(|ring| {
+        let mut probe = Probe::new();
+        ring.submitter().register_probe(&mut probe)?;
+        Ok((ring, probe))
+    });
+    match ring {
+        Ok((ring, probe)) if probe.is_supported(opcode::Statx::CODE) => Some(ring),
+        Ok(_) => {
+            log::info!("io_uring doesn't support statx, looking up files one at a time");
+            None
+        }
+        Err(error) => {
+            log::info!(
+                "Couldn't set up io_uring, looking up files one at a time - {}",
+                error
+            );
+            None
+        }
+    }
+}
+
+/// Look up several children of a directory at once. Returns None if io_uring isn't available, in
+/// which case they have to be looked up one at a time instead.
+pub fn stat_all_at(dir: &File, names: &[&CStr]) -> Option<Vec<Result<(FileType, FileId)>>> {
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        let mut output = Vec::with_capacity(names.len());
+        for chunk in names.chunks(RING_SIZE as usize) {
+            let uring = ring.get_or_insert_with(setup).as_mut()?;
+            match stat_chunk(uring, dir, chunk) {
+                Ok(results) => output.extend(results),
+                Err(error) => {
+                    log::warn!("Giving up on io_uring - {}", error);
+                    *ring = Some(None);
+                    return None;
+                }
+            }
+        }
+        Some(output)
+    })
+}
+
+/// Submit lookups for up to RING_SIZE names, and wait for all of them to complete. Errors are for
+/// the ring itself, rather than the individual lookups, and the ring mustn't be used afterwards.
+fn stat_chunk(
+    ring: &mut IoUring,
+    dir: &File,
+    names: &[&CStr],
+) -> io::Result<Vec<Result<(FileType, FileId)>>> {
+    // the kernel reads the names and writes the buffers after submission, so both are owned here,
+    // where they can be kept alive if the lookups can't be waited for
+    let names = names
+        .iter()
+        .map(|&name| name.to_owned())
+        .collect::<Vec<_>>();
+    let mut buffers = names
+        .iter()
+        .map(|_| MaybeUninit::<libc::statx>::uninit())
+        .collect::<Vec<_>>();
+    let mut results = vec![None; names.len()];
+
+    {
+        let mut submission = ring.submission();
+        for (index, (name, buffer)) in names.iter().zip(&mut buffers).enumerate() {
+            let entry = opcode::Statx::new(
+                types::Fd(dir.fd),
+                name.as_ptr(),
+                buffer.as_mut_ptr() as *mut types::statx,
+            )
+            .flags(libc::AT_SYMLINK_NOFOLLOW)
+            .mask(libc::STATX_TYPE | libc::STATX_INO)
+            .build()
+            .user_data(index as u64);
+            unsafe { submission.push(&entry) }.expect("Submission queue is full");
+        }
+    }
+
+    let mut remaining = names.len();
+    while remaining > 0 {
+        if let Err(error) = ring.submit_and_wait(remaining) {
+            if error.kind() != io::ErrorKind::Interrupted {
+                // some of the lookups may still be in flight, reading their names and writing to
+                // their buffers later
+                mem::forget(names);
+                mem::forget(buffers);
+                return Err(error);
+            }
+        }
+        for entry in ring.completion() {
+            results[entry.user_data() as usize] = Some(entry.result());
+            remaining -= 1;
+        }
+    }
+
+    Ok(results
+        .into_iter()
+        .zip(&names)
+        .zip(buffers)
+        .map(|((result, name), buffer)| {
+            let result = result.expect("Lookup didn't complete");
+            if result < 0 {
+                let error = Error::from(io::Error::from_raw_os_error(-result));
+                return Err(error.context(Operation::Stat, as_path(name)));
+            }
+            // the lookup succeeded, so the kernel filled in the buffer
+            let buffer = unsafe { buffer.assume_init() };
+            let file_type = FileType::try_from(libc::mode_t::from(buffer.stx_mode))?;
+            let id = FileId {
+                dev: libc::makedev(buffer.stx_dev_major, buffer.stx_dev_minor),
+                inode: buffer.stx_ino,
+            };
+            Ok((file_type, id))
+        })
+        .collect())
+}
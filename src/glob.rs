@@ -1,35 +1,113 @@
-use self::parser::{Ast, Segment};
+use self::parser::{Ast, Extended, Segment};
 pub use self::tokenizer::TokenSet;
 use crate::error::{Error, Result};
-use std::{ffi::OsStr, iter};
+use std::{
+    ffi::{OsStr, OsString},
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path},
+};
 
-use either::Either;
 use itertools::Itertools;
-use regex::Regex;
+use regex::bytes::{Regex, RegexSet};
 use slab::Slab;
 use std::collections::HashMap;
 
 mod parser;
 mod tokenizer;
 
+/// How a segment of a glob matches names.
+enum Matcher {
+    Regex(Regex),
+    Extended(Extended),
+}
+
+impl Matcher {
+    /// A regex that matches every name that the segment matches. This is only exact for segments
+    /// that don't use extended globs - see is_exact.
+    fn regex(&self) -> &Regex {
+        match self {
+            Matcher::Regex(regex) => regex,
+            Matcher::Extended(extended) => extended.prefilter(),
+        }
+    }
+
+    /// Whether a name matching the regex is enough for it to match the segment.
+    fn is_exact(&self) -> bool {
+        matches!(self, Matcher::Regex(_))
+    }
+
+    fn is_match(&self, name: &[u8]) -> bool {
+        let name = &parser::escape_invalid(name)[..];
+        match self {
+            Matcher::Regex(regex) => regex.is_match(name),
+            Matcher::Extended(extended) => {
+                extended.prefilter().is_match(name) && extended.is_match(name)
+            }
+        }
+    }
+}
+
 /// A single segment of a glob, used to match against segments of a path.
 struct Glob {
-    segment: Option<Regex>,
+    segment: Option<Matcher>,
+    // the key of the first segment of the pattern this segment was parsed from
+    first: usize,
     negated: bool,
     trailing_slash: bool,
     relative: bool,
 }
 
 /// A key that indexes into the GlobArena.
-#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct GlobKey(usize);
 
+/// A group of globs that are matched against a name together, by combining the regexes of their
+/// current segments into a single regex set.
+#[derive(Debug)]
+pub struct GlobSet {
+    keys: Vec<GlobKey>,
+    // for each key, the index of its regex within the set (or None for ** segments)
+    set_indices: Vec<Option<usize>>,
+    matcher: RegexSet,
+}
+
+impl GlobSet {
+    /// The globs that make up this set.
+    pub fn keys(&self) -> &[GlobKey] {
+        &self.keys
+    }
+
+    /// Test a name against every glob in the set at once. Yields the key of each glob, alongside
+    /// whether the glob's current segment matched the name.
+    fn matches<'a>(&'a self, name: &OsStr) -> impl Iterator<Item = (usize, bool)> + 'a {
+        let matches = self
+            .matcher
+            .matches(&parser::escape_invalid(name.as_bytes()));
+        self.keys
+            .iter()
+            .zip(self.set_indices.iter())
+            .map(move |(&GlobKey(key), index)| {
+                let is_match = index.is_none_or(|index| matches.matched(index));
+                (key, is_match)
+            })
+    }
+}
+
+/// A pattern as it was given to compile_glob, along with whether it ignores case and whether it
+/// uses extended glob syntax.
+type Source = (OsString, bool, bool);
+
 /// An arena of glob segments.
 pub struct GlobArena {
     // The regexes for each glob section - if they aren't ** sections
     storage: Slab<Glob>,
     // for each glob segment, tell me if a segment follows it in the pattern that it was parsed from
     children: HashMap<usize, usize>,
+    // the first segment of each compiled pattern, so that identical patterns are only stored once
+    patterns: HashMap<Source, usize>,
+    // for the first segment of each compiled pattern, the number of times that it has been
+    // compiled without being removed, and the pattern it came from
+    references: HashMap<usize, (usize, Source)>,
 }
 
 impl GlobArena {
@@ -38,44 +116,88 @@ impl GlobArena {
         GlobArena {
             storage: Slab::new(),
             children: HashMap::new(),
+            patterns: HashMap::new(),
+            references: HashMap::new(),
         }
     }
 
     /// Compile a new glob. Returns, if the compilation is successful, a key by which to index into
-    /// the glob.
-    pub fn compile_glob(&mut self, glob: &str) -> Result<GlobKey> {
+    /// the glob. If case_insensitive is set, the glob will match names regardless of their case.
+    /// Compiling a glob that is already in the arena returns the existing key, and the glob will
+    /// then stay in the arena until it has been removed as many times as it was compiled.
+    pub fn compile_glob(
+        &mut self,
+        glob: impl AsRef<OsStr>,
+        case_insensitive: bool,
+    ) -> Result<GlobKey> {
+        self.compile(glob.as_ref(), case_insensitive, false)
+    }
+
+    /// Compile a glob that may also use ksh-style extended globs: `?(a|b)`, `*(a|b)`, `+(a|b)` and
+    /// `@(a|b)` match zero or one, zero or more, one or more, or exactly one of the patterns in
+    /// the list, and `!(a|b)` matches anything except one of them. A glob that starts with `!(` is
+    /// not negated.
+    pub fn compile_extended_glob(
+        &mut self,
+        glob: impl AsRef<OsStr>,
+        case_insensitive: bool,
+    ) -> Result<GlobKey> {
+        self.compile(glob.as_ref(), case_insensitive, true)
+    }
+
+    fn compile(&mut self, glob: &OsStr, case_insensitive: bool, extended: bool) -> Result<GlobKey> {
+        let source = (glob.to_os_string(), case_insensitive, extended);
+        if let Some(&key) = self.patterns.get(&source) {
+            self.references.get_mut(&key).unwrap().0 += 1;
+            return Ok(GlobKey(key));
+        }
+
+        let ast = if extended {
+            parser::parse_extended(glob.as_bytes(), case_insensitive)?
+        } else {
+            parser::parse(glob.as_bytes(), case_insensitive)?
+        };
+        let key = self.compile_new_glob(glob, ast)?;
+        self.patterns.insert(source.clone(), key.0);
+        self.references.insert(key.0, (1, source));
+        Ok(key)
+    }
+
+    fn compile_new_glob(&mut self, glob: &OsStr, ast: Ast) -> Result<GlobKey> {
         let Ast {
             starts_negated,
             segments,
-        } = parser::parse(glob)?;
+        } = ast;
 
-        let (fixed_path, segments) = match &segments[..] {
-            [Segment::Separator, ..] => (true, segments.into_iter().skip(1)),
-            [rest @ ..] => (rest.len() > 2, segments.into_iter().skip(0)),
+        let (fixed_path, skip) = match &segments[..] {
+            [Segment::Separator, ..] => (true, 1),
+            rest => (rest.len() > 2, 0),
         };
 
-        let mut segments = segments
+        let segments = segments
+            .into_iter()
+            .skip(skip)
             .batching(|it| {
                 let start = it.next()?;
                 let output = match start {
                     Segment::Separator => {
                         return Some(Err(Error::InvalidGlobCompile(
-                            glob.to_string(),
+                            glob.to_string_lossy().into_owned(),
                             "unexpected /",
                         )))
                     }
                     other_segment => {
-                        let segment = if let Segment::Pattern(regex) = other_segment {
-                            Some(regex)
-                        } else {
-                            None
+                        let segment = match other_segment {
+                            Segment::Pattern(regex) => Some(Matcher::Regex(regex)),
+                            Segment::Extended(extended) => Some(Matcher::Extended(extended)),
+                            _ => None,
                         };
                         let trailing_slash = match it.next() {
                             Some(Segment::Separator) => true,
                             None => false,
                             _ => {
                                 return Some(Err(Error::InvalidGlobCompile(
-                                    glob.to_string(),
+                                    glob.to_string_lossy().into_owned(),
                                     "/ needed between sections",
                                 )))
                             }
@@ -92,14 +214,16 @@ impl GlobArena {
         let mut latest_key = None;
 
         for (segment, trailing) in segments {
-            let glob = Glob {
-                segment: segment,
+            let entry = self.storage.vacant_entry();
+            let key = entry.key();
+            let GlobKey(first) = *first_key.get_or_insert(GlobKey(key));
+            entry.insert(Glob {
+                segment,
+                first,
                 negated: starts_negated,
                 trailing_slash: trailing,
                 relative: !fixed_path,
-            };
-            let key = self.storage.insert(glob);
-            first_key.get_or_insert(GlobKey(key));
+            });
 
             if let Some(old_key) = latest_key {
                 self.children.insert(old_key, key);
@@ -107,29 +231,111 @@ impl GlobArena {
             latest_key = Some(key);
         }
 
-        first_key.ok_or_else(|| Error::InvalidGlobCompile(glob.to_string(), "no glob segments"))
+        first_key.ok_or_else(|| {
+            Error::InvalidGlobCompile(glob.to_string_lossy().into_owned(), "no glob segments")
+        })
     }
 
-    // Some(true) means that the glob explicitly matches this file. Some(false) means that the glob
-    // explicitly matches this file, but was negated. None means that the glob did not match this
-    // file.
-    pub fn match_file(&self, GlobKey(key): GlobKey, name: &OsStr, is_dir: bool) -> Option<bool> {
-        if self.children.get(&key).is_some() {
-            return None;
+    /// Remove a glob, using the key that was returned by compile_glob. Once the glob has been
+    /// removed as many times as it was compiled, every segment of the glob is freed and their keys
+    /// may be reused by globs compiled later. Returns whether the glob was freed.
+    pub fn remove(&mut self, GlobKey(key): GlobKey) -> bool {
+        let (references, _) = self
+            .references
+            .get_mut(&key)
+            .expect("Removed a glob that is not in the arena");
+        *references -= 1;
+        if *references > 0 {
+            return false;
         }
-        let glob = &self.storage[key];
-        let name = name.to_str()?;
 
-        let is_match = match &glob.segment {
-            Some(regex) => regex.is_match(name),
+        let (_, source) = self.references.remove(&key).unwrap();
+        self.patterns.remove(&source);
+
+        let mut next = Some(key);
+        while let Some(key) = next {
+            self.storage.remove(key);
+            next = self.children.remove(&key);
+        }
+
+        true
+    }
+
+    /// Whether a glob segment is still in the arena.
+    pub fn contains(&self, GlobKey(key): GlobKey) -> bool {
+        self.storage.contains(key)
+    }
+
+    /// Compile a group of globs into a set, so that they can all be matched against a name in a
+    /// single pass.
+    pub fn compile_set(&self, keys: Vec<GlobKey>) -> GlobSet {
+        let mut patterns = Vec::new();
+        let set_indices = keys
+            .iter()
+            .map(|&GlobKey(key)| {
+                self.storage[key].segment.as_ref().map(|matcher| {
+                    patterns.push(matcher.regex().as_str());
+                    patterns.len() - 1
+                })
+            })
+            .collect();
+        let matcher = RegexSet::new(patterns).expect("Glob segments are valid regexes");
+
+        GlobSet {
+            keys,
+            set_indices,
+            matcher,
+        }
+    }
+
+    fn is_segment_match(&self, key: usize, name: &OsStr) -> bool {
+        match &self.storage[key].segment {
+            Some(matcher) => matcher.is_match(name.as_bytes()),
             None => true,
-        };
+        }
+    }
 
-        if is_match {
+    /// Check a match found by a glob set, which only tests the regex of each segment.
+    fn confirm_set_match(&self, key: usize, name: &OsStr, is_match: bool) -> bool {
+        match &self.storage[key].segment {
+            Some(matcher) if is_match && !matcher.is_exact() => matcher.is_match(name.as_bytes()),
+            _ => is_match,
+        }
+    }
+
+    fn file_result(&self, key: usize, is_match: bool, is_dir: bool) -> Option<bool> {
+        let glob = &self.storage[key];
+        // a glob with a trailing slash only matches directories
+        if self.children.contains_key(&key) || !is_match || (glob.trailing_slash && !is_dir) {
+            None
+        } else {
             Some(!glob.negated)
+        }
+    }
+
+    fn dir_result(&self, key: usize, is_match: bool) -> impl Iterator<Item = GlobKey> {
+        let glob = &self.storage[key];
+
+        let child_match = if is_match {
+            self.children.get(&key).cloned().map(GlobKey)
         } else {
             None
-        }
+        };
+
+        let loop_match = if glob.relative || glob.segment.is_none() {
+            Some(GlobKey(key))
+        } else {
+            None
+        };
+
+        child_match.into_iter().chain(loop_match)
+    }
+
+    // Some(true) means that the glob explicitly matches this file. Some(false) means that the glob
+    // explicitly matches this file, but was negated. None means that the glob did not match this
+    // file.
+    pub fn match_file(&self, GlobKey(key): GlobKey, name: &OsStr, is_dir: bool) -> Option<bool> {
+        self.file_result(key, self.is_segment_match(key, name), is_dir)
     }
 
     /// Find the glob that can be used to match against the children of this file. Returns either
@@ -141,32 +347,136 @@ impl GlobArena {
         GlobKey(key): GlobKey,
         name: &OsStr,
     ) -> Option<impl Iterator<Item = GlobKey>> {
-        let glob = &self.storage[key];
-        let name = name.to_str()?;
+        let mut output = self
+            .dir_result(key, self.is_segment_match(key, name))
+            .peekable();
 
-        let is_child_match = match &glob.segment {
-            Some(regex) => regex.is_match(name),
-            None => true,
-        };
-        let child_match = if is_child_match {
-            self.children.get(&key).cloned().map(GlobKey)
-        } else {
-            None
+        output.peek()?;
+        Some(output)
+    }
+
+    /// Match a name against every glob in a set, giving the result that match_file would give for
+    /// each glob that matches, alongside the key of that glob.
+    pub fn match_file_set<'a>(
+        &'a self,
+        set: &'a GlobSet,
+        name: &'a OsStr,
+        is_dir: bool,
+    ) -> impl Iterator<Item = (GlobKey, bool)> + 'a {
+        set.matches(name).filter_map(move |(key, is_match)| {
+            let is_match = self.confirm_set_match(key, name, is_match);
+            self.file_result(key, is_match, is_dir)
+                .map(|result| (GlobKey(key), result))
+        })
+    }
+
+    /// Find the globs that should be used to match against the children of this directory, as
+    /// match_dir would for each glob in the set.
+    pub fn match_dir_set(&self, set: &GlobSet, name: &OsStr) -> Vec<GlobKey> {
+        set.matches(name)
+            .flat_map(|(key, is_match)| {
+                self.dir_result(key, self.confirm_set_match(key, name, is_match))
+            })
+            .collect()
+    }
+
+    /// Match a whole relative path against a glob, walking through its directories with match_dir
+    /// and then testing the final component with match_file. Returns None for paths that do not
+    /// match, or that leave the directory they are relative to.
+    pub fn match_path(&self, key: GlobKey, path: &Path, is_dir: bool) -> Option<bool> {
+        let mut names = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => names.push(name),
+                Component::CurDir => continue,
+                _ => return None,
+            }
+        }
+
+        let (last, parents) = names.split_last()?;
+        let mut keys = vec![key];
+        for name in parents {
+            keys = keys
+                .into_iter()
+                .filter_map(|key| self.match_dir(key, name))
+                .flatten()
+                .collect();
+            keys.sort_unstable();
+            keys.dedup();
+        }
+
+        keys.into_iter()
+            .filter_map(|key| self.match_file(key, last, is_dir))
+            .next()
+    }
+
+    /// Find the key that was returned by compile_glob for the pattern that a glob segment was
+    /// parsed from.
+    pub fn pattern_key(&self, GlobKey(key): GlobKey) -> GlobKey {
+        GlobKey(self.storage[key].first)
+    }
+
+    /// Whether a glob was negated with a leading `!`.
+    pub fn is_negated(&self, GlobKey(key): GlobKey) -> bool {
+        self.storage[key].negated
+    }
+}
+
+/// Options that change how a pattern is compiled.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PatternOptions {
+    /// Match paths regardless of their case.
+    pub ignore_case: bool,
+    /// Allow ksh-style extended globs, such as `!(*_test).rs` or `@(src|tests)/**`.
+    pub extended: bool,
+}
+
+/// A single compiled glob, which can be matched against relative paths.
+pub struct Pattern {
+    arena: GlobArena,
+    key: GlobKey,
+}
+
+impl Pattern {
+    /// Compile a glob, using the same syntax as a line from a gitignore file.
+    pub fn new(glob: impl AsRef<OsStr>) -> Result<Self> {
+        Self::with_case(glob, false)
+    }
+
+    /// Compile a glob. If case_insensitive is set, the pattern will match paths regardless of
+    /// their case.
+    pub fn with_case(glob: impl AsRef<OsStr>, case_insensitive: bool) -> Result<Self> {
+        let options = PatternOptions {
+            ignore_case: case_insensitive,
+            ..PatternOptions::default()
         };
+        Self::with_options(glob, options)
+    }
 
-        let loop_match = if glob.relative || glob.segment.is_none() {
-            Some(GlobKey(key))
+    /// Compile a glob with the given options.
+    pub fn with_options(glob: impl AsRef<OsStr>, options: PatternOptions) -> Result<Self> {
+        let mut arena = GlobArena::new();
+        let key = if options.extended {
+            arena.compile_extended_glob(glob, options.ignore_case)?
         } else {
-            None
+            arena.compile_glob(glob, options.ignore_case)?
         };
 
-        if child_match.is_none() && loop_match.is_none() {
-            return None;
-        }
+        Ok(Pattern { arena, key })
+    }
 
-        let output = child_match.into_iter().chain(loop_match.into_iter());
+    /// Test whether a path, relative to the directory the pattern applies to, matches the pattern.
+    /// Negation is ignored here - see is_negated.
+    pub fn matches(&self, path: impl AsRef<Path>, is_dir: bool) -> bool {
+        self.arena
+            .match_path(self.key, path.as_ref(), is_dir)
+            .is_some()
+    }
 
-        Some(output)
+    /// Whether the pattern was negated with a leading `!`, meaning that paths matching it should
+    /// be included rather than excluded.
+    pub fn is_negated(&self) -> bool {
+        self.arena.is_negated(self.key)
     }
 }
 
@@ -177,13 +487,13 @@ mod test {
     #[test]
     fn no_double_slash() {
         let mut arena = GlobArena::new();
-        arena.compile_glob("path//to/file").unwrap_err();
+        arena.compile_glob("path//to/file", false).unwrap_err();
     }
 
     #[test]
     fn path_to_file() {
         let mut arena = GlobArena::new();
-        let top_key = arena.compile_glob("path/to/file.txt").unwrap();
+        let top_key = arena.compile_glob("path/to/file.txt", false).unwrap();
 
         assert_eq!(None, arena.match_file(top_key, "path".as_ref(), true));
         let &mid_key = match &arena
@@ -214,7 +524,7 @@ mod test {
     #[test]
     fn plain_filename() {
         let mut arena = GlobArena::new();
-        let key = arena.compile_glob("file.txt").unwrap();
+        let key = arena.compile_glob("file.txt", false).unwrap();
 
         assert_eq!(
             Some(true),
@@ -234,7 +544,7 @@ mod test {
     #[test]
     fn uses_star_star() {
         let mut arena = GlobArena::new();
-        let key = arena.compile_glob("**/index.js").unwrap();
+        let key = arena.compile_glob("**/index.js", false).unwrap();
 
         assert_eq!(None, arena.match_file(key, "index.js".as_ref(), false));
         let child_key = match &arena
@@ -254,4 +564,220 @@ mod test {
             arena.match_file(child_key, "index.js".as_ref(), false)
         );
     }
+
+    #[test]
+    fn non_utf8_names() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let mut arena = GlobArena::new();
+        let key = arena.compile_glob("*.o", false).unwrap();
+
+        let name = OsStr::from_bytes(b"\xFFobject.o");
+        assert_eq!(Some(true), arena.match_file(key, name, false));
+        assert!(arena.match_dir(key, name).is_some());
+
+        let key = arena
+            .compile_glob(OsStr::from_bytes(b"\xFF*"), false)
+            .unwrap();
+        assert_eq!(Some(true), arena.match_file(key, name, false));
+        assert_eq!(None, arena.match_file(key, "object.o".as_ref(), false));
+    }
+
+    #[test]
+    fn multibyte_names() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let mut arena = GlobArena::new();
+        let mut matches = |glob: &str, name: &[u8]| {
+            let key = arena.compile_extended_glob(glob, false).unwrap();
+            arena.match_file(key, OsStr::from_bytes(name), false) == Some(true)
+        };
+
+        // wildcards match whole characters, never the bytes within one
+        assert!(matches("?", "é".as_bytes()));
+        assert!(!matches("??", "é".as_bytes()));
+        assert!(matches("?", "日".as_bytes()));
+        assert!(!matches("???", "日".as_bytes()));
+        assert!(matches("*", "日本".as_bytes()));
+        assert!(matches("?*?", "日本".as_bytes()));
+        assert!(!matches("?*??", "日本".as_bytes()));
+        assert!(!matches("[!é]", "é".as_bytes()));
+        assert!(!matches("[!é]?", "é".as_bytes()));
+        assert!(matches("[!a]", "é".as_bytes()));
+        assert!(!matches("!(é)", "é".as_bytes()));
+        assert!(!matches("!(?)", "é".as_bytes()));
+
+        // bytes that aren't part of a valid character are matched one at a time
+        assert!(matches("?", b"\xFF"));
+        assert!(matches("??", b"a\xC3"));
+        assert!(!matches("?", b"a\xC3"));
+        assert!(matches("??", b"\xC3\xA9\xFF"));
+        assert!(matches("??", b"\xC3\xA9\xA9"));
+        assert!(!matches("???", b"\xC3\xA9\xA9"));
+        assert!(matches("[!a]", b"\x80"));
+        assert!(matches("é*", b"\xC3\xA9\xF5\xFF"));
+    }
+
+    #[test]
+    fn glob_set_matches_individual_globs() {
+        let mut arena = GlobArena::new();
+        let keys = ["*.o", "!keep.o", "target/**", "**/index.js", "/build"]
+            .iter()
+            .map(|glob| arena.compile_glob(glob, false).unwrap())
+            .collect::<Vec<_>>();
+        let set = arena.compile_set(keys.clone());
+
+        for name in &["main.o", "keep.o", "target", "index.js", "build", "src"] {
+            let name = name.as_ref();
+            let individual = keys
+                .iter()
+                .filter_map(|&key| arena.match_file(key, name, false))
+                .collect::<Vec<_>>();
+            let combined = arena
+                .match_file_set(&set, name, false)
+                .map(|(_, result)| result)
+                .collect::<Vec<_>>();
+            assert_eq!(individual, combined);
+
+            let individual = keys
+                .iter()
+                .filter_map(|&key| arena.match_dir(key, name))
+                .flatten()
+                .collect::<Vec<_>>();
+            assert_eq!(individual, arena.match_dir_set(&set, name));
+        }
+    }
+
+    #[test]
+    fn match_full_paths() {
+        use super::Pattern;
+
+        let pattern = Pattern::new("src/**/*.rs").unwrap();
+        assert!(pattern.matches("src/glob/parser.rs", false));
+        assert!(pattern.matches("src/a/b/c.rs", false));
+        assert!(!pattern.matches("bin/glob/main.rs", false));
+        assert!(!pattern.matches("src/glob/main.c", false));
+        assert!(!pattern.matches("../src/glob/main.rs", false));
+
+        let pattern = Pattern::new("*.o").unwrap();
+        assert!(pattern.matches("main.o", false));
+        assert!(pattern.matches("target/debug/main.o", false));
+
+        let pattern = Pattern::new("/build").unwrap();
+        assert!(pattern.matches("build", true));
+        assert!(!pattern.matches("src/build", true));
+
+        let pattern = Pattern::new("!keep.o").unwrap();
+        assert!(pattern.is_negated());
+        assert!(pattern.matches("keep.o", false));
+    }
+
+    #[test]
+    fn remove_glob() {
+        let mut arena = GlobArena::new();
+        let key = arena.compile_glob("path/to/file.txt", false).unwrap();
+        let other = arena.compile_glob("*.o", false).unwrap();
+        assert!(arena.remove(key));
+
+        assert!(arena.storage.len() == 1);
+        assert!(arena.children.is_empty());
+        assert_eq!(
+            Some(true),
+            arena.match_file(other, "main.o".as_ref(), false)
+        );
+    }
+
+    #[test]
+    fn deduplicate_globs() {
+        let mut arena = GlobArena::new();
+        let key = arena.compile_glob("target/**", false).unwrap();
+        let same_key = arena.compile_glob("target/**", false).unwrap();
+        let other_key = arena.compile_glob("target/**", true).unwrap();
+
+        assert_eq!(key, same_key);
+        assert_ne!(key, other_key);
+        assert_eq!(4, arena.storage.len());
+
+        assert!(!arena.remove(key));
+        assert!(arena.contains(key));
+        assert!(arena.remove(same_key));
+        assert!(!arena.contains(key));
+        assert_eq!(2, arena.storage.len());
+
+        let new_key = arena.compile_glob("target/**", false).unwrap();
+        assert!(arena.remove(new_key));
+        assert!(arena.remove(other_key));
+        assert!(arena.storage.is_empty());
+        assert!(arena.patterns.is_empty());
+    }
+
+    #[test]
+    fn extended_patterns() {
+        use super::{Pattern, PatternOptions};
+
+        let extended = |glob| {
+            let options = PatternOptions {
+                extended: true,
+                ..PatternOptions::default()
+            };
+            Pattern::with_options(glob, options).unwrap()
+        };
+
+        let pattern = extended("src/!(tests)/**");
+        assert!(!pattern.is_negated());
+        assert!(pattern.matches("src/glob/parser.rs", false));
+        assert!(!pattern.matches("src/tests/parser.rs", false));
+        assert!(pattern.matches("src/tests2/parser.rs", false));
+
+        let pattern = extended("!(*_test).rs");
+        assert!(pattern.matches("main.rs", false));
+        assert!(!pattern.matches("main_test.rs", false));
+        assert!(!pattern.matches("main.c", false));
+
+        let pattern = extended("*.@(c|h)");
+        assert!(pattern.matches("main.c", false));
+        assert!(pattern.matches("main.h", false));
+        assert!(!pattern.matches("main.o", false));
+
+        // without the option, the same patterns are plain globs
+        let pattern = Pattern::new("*.@(c|h)").unwrap();
+        assert!(!pattern.matches("main.c", false));
+        assert!(pattern.matches("main.@(c|h)", false));
+        assert!(Pattern::new("!(tests)").unwrap().is_negated());
+    }
+
+    #[test]
+    fn extended_glob_sets() {
+        let mut arena = GlobArena::new();
+        let keys = vec![
+            arena.compile_extended_glob("!(*.rs)", false).unwrap(),
+            arena.compile_glob("*.rs", false).unwrap(),
+        ];
+        let set = arena.compile_set(keys.clone());
+
+        for name in &["main.rs", "main.c", "rs"] {
+            let expected = keys
+                .iter()
+                .filter_map(|&key| {
+                    arena
+                        .match_file(key, name.as_ref(), false)
+                        .map(|r| (key, r))
+                })
+                .collect::<Vec<_>>();
+            let actual = arena
+                .match_file_set(&set, name.as_ref(), false)
+                .collect::<Vec<_>>();
+            assert_eq!(expected, actual);
+        }
+    }
+
+    #[test]
+    fn trailing_slash_matches_directories() {
+        use super::Pattern;
+
+        let pattern = Pattern::new("build/").unwrap();
+        assert!(pattern.matches("build", true));
+        assert!(!pattern.matches("build", false));
+        assert!(pattern.matches("src/build", true));
+    }
 }
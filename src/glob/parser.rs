@@ -3,9 +3,9 @@ use crate::{
     glob::tokenizer::{Token, TokenSet, Tokenizer},
 };
 
-use regex::Regex;
+use regex::bytes::Regex;
 use regex_syntax::hir::{self, Hir};
-use std::iter;
+use std::{borrow::Cow, collections::HashSet, iter, mem, str};
 
 #[derive(Debug)]
 pub struct Ast {
@@ -16,71 +16,470 @@ pub struct Ast {
 #[derive(Debug)]
 pub enum Segment {
     Pattern(Regex),
+    Extended(Extended),
     Anything,
     Separator,
 }
 
-fn question() -> Hir {
+/// A segment containing a `!(...)` pattern list, which matches any name that none of the patterns
+/// in the list match. Regexes can't express this, so the segment is split up into parts around
+/// each of these lists, and a name matches if it can be divided up between the parts.
+#[derive(Debug)]
+pub struct Extended {
+    // matches every name that the segment matches (and others), with `*` in place of each list
+    prefilter: Regex,
+    // the regex for each part, along with whether the part is a negated list
+    parts: Vec<(Regex, bool)>,
+}
+
+impl Extended {
+    fn new(pieces: Vec<Piece>, case_insensitive: bool) -> Self {
+        let prefilter = pieces
+            .iter()
+            .map(|piece| match piece {
+                Piece::Hir(hir) => hir.clone(),
+                Piece::Not(_) => star(),
+            })
+            .collect();
+        let prefilter = anchored(Hir::concat(prefilter), case_insensitive);
+
+        let mut parts = Vec::new();
+        let mut run = Vec::new();
+        for piece in pieces {
+            match piece {
+                Piece::Hir(hir) => run.push(hir),
+                Piece::Not(hir) => {
+                    if !run.is_empty() {
+                        let hir = Hir::concat(mem::take(&mut run));
+                        parts.push((anchored(hir, case_insensitive), false));
+                    }
+                    parts.push((anchored(hir, case_insensitive), true));
+                }
+            }
+        }
+        if !run.is_empty() {
+            parts.push((anchored(Hir::concat(run), case_insensitive), false));
+        }
+
+        Extended { prefilter, parts }
+    }
+
+    /// A regex that matches every name that the segment matches, but that may match others too.
+    pub fn prefilter(&self) -> &Regex {
+        &self.prefilter
+    }
+
+    /// Test whether an escaped name matches the segment.
+    pub fn is_match(&self, name: &[u8]) -> bool {
+        let boundaries = char_boundaries(name);
+        let mut failed = HashSet::new();
+        self.match_parts(name, &boundaries, 0, 0, &mut failed)
+    }
+
+    /// Test whether the end of a name, from the given character boundary, matches the parts from
+    /// the given index onwards. Pairs of part and boundary that are known not to match are kept
+    /// in failed, so that each is only tried once.
+    fn match_parts(
+        &self,
+        name: &[u8],
+        boundaries: &[usize],
+        part: usize,
+        start: usize,
+        failed: &mut HashSet<(usize, usize)>,
+    ) -> bool {
+        let (regex, negated) = match self.parts.get(part) {
+            Some(part) => part,
+            None => return boundaries[start] == name.len(),
+        };
+        if failed.contains(&(part, start)) {
+            return false;
+        }
+
+        let is_match = (start..boundaries.len()).any(|end| {
+            let section = &name[boundaries[start]..boundaries[end]];
+            regex.is_match(section) != *negated
+                && self.match_parts(name, boundaries, part + 1, end, failed)
+        });
+        if !is_match {
+            failed.insert((part, start));
+        }
+
+        is_match
+    }
+}
+
+/// The byte offsets in an escaped name at which its characters start, followed by its length.
+/// Escaped bytes count as characters of their own.
+fn char_boundaries(name: &[u8]) -> Vec<usize> {
+    let mut output = Vec::new();
+    let mut offset = 0;
+
+    while offset < name.len() {
+        output.push(offset);
+        offset += match name[offset] {
+            ESCAPE => 2,
+            byte if byte < 0x80 => 1,
+            byte if byte >= 0xF0 => 4,
+            byte if byte >= 0xE0 => 3,
+            _ => 2,
+        };
+    }
+    output.push(name.len());
+    output
+}
+
+/// Names that aren't valid UTF-8 are matched after putting this byte in front of every byte that
+/// isn't part of a valid character. It never appears in valid UTF-8, so each invalid byte is
+/// matched as a character of its own, and the bytes of valid characters can't be mistaken for
+/// invalid ones.
+const ESCAPE: u8 = 0xFF;
+
+/// Escape the bytes of a name that aren't part of a valid UTF-8 character, so that it can be
+/// matched against a glob's regexes.
+pub fn escape_invalid(name: &[u8]) -> Cow<'_, [u8]> {
+    if str::from_utf8(name).is_ok() {
+        return Cow::Borrowed(name);
+    }
+
+    let mut output = Vec::with_capacity(name.len() * 2);
+    for chunk in name.utf8_chunks() {
+        output.extend_from_slice(chunk.valid().as_bytes());
+        for &byte in chunk.invalid() {
+            output.extend_from_slice(&[ESCAPE, byte]);
+        }
+    }
+    Cow::Owned(output)
+}
+
+/// A part of a segment: either something that a regex can match, or the patterns from a `!(...)`
+/// pattern list.
+enum Piece {
+    Hir(Hir),
+    Not(Hir),
+}
+
+/// Every character other than the path separator.
+fn not_separator() -> hir::ClassUnicode {
     let mut class = hir::ClassUnicode::new(iter::once(hir::ClassUnicodeRange::new('/', '/')));
     class.negate();
-    Hir::class(hir::Class::Unicode(class))
+    class
 }
 
-fn star() -> Hir {
+/// An escaped byte of a name, which wasn't part of a valid UTF-8 character.
+fn escaped_byte() -> Hir {
+    let any = hir::ClassBytes::new(iter::once(hir::ClassBytesRange::new(0, 0xFF)));
+    Hir::concat(vec![
+        Hir::literal(hir::Literal::Byte(ESCAPE)),
+        Hir::class(hir::Class::Bytes(any)),
+    ])
+}
+
+/// Match either a character from the class, or any escaped byte that is not part of a valid
+/// character.
+fn class_or_invalid(class: hir::ClassUnicode) -> Hir {
+    let alternation =
+        Hir::alternation(vec![Hir::class(hir::Class::Unicode(class)), escaped_byte()]);
+    Hir::group(hir::Group {
+        kind: hir::GroupKind::NonCapturing,
+        hir: Box::new(alternation),
+    })
+}
+
+/// Convert a literal section of a glob into regex literals, escaping any bytes that are not valid
+/// UTF-8 in the same way as the names they're matched against.
+fn literal(mut bytes: &[u8]) -> Vec<Hir> {
+    let mut output = Vec::new();
+
+    loop {
+        let (valid, invalid, rest) = match str::from_utf8(bytes) {
+            Ok(valid) => (valid, &[][..], &[][..]),
+            Err(error) => {
+                let (valid, rest) = bytes.split_at(error.valid_up_to());
+                let invalid_len = error.error_len().unwrap_or(rest.len());
+                let (invalid, rest) = rest.split_at(invalid_len);
+                (str::from_utf8(valid).unwrap(), invalid, rest)
+            }
+        };
+
+        output.extend(
+            valid
+                .chars()
+                .map(|letter| Hir::literal(hir::Literal::Unicode(letter))),
+        );
+        for &byte in invalid {
+            output.push(Hir::literal(hir::Literal::Byte(ESCAPE)));
+            output.push(Hir::literal(hir::Literal::Byte(byte)));
+        }
+
+        if rest.is_empty() {
+            return output;
+        }
+        bytes = rest;
+    }
+}
+
+fn question() -> Hir {
+    class_or_invalid(not_separator())
+}
+
+fn repeat(hir: Hir, kind: hir::RepetitionKind) -> Hir {
     Hir::repetition(hir::Repetition {
-        kind: hir::RepetitionKind::ZeroOrMore,
+        kind,
         greedy: true,
-        hir: Box::new(question()),
+        hir: Box::new(hir),
     })
 }
 
-fn parse_charset(tokens: &mut Tokenizer) -> Result<Hir> {
+fn star() -> Hir {
+    repeat(question(), hir::RepetitionKind::ZeroOrMore)
+}
+
+/// Build a regex that has to match the whole of a name.
+fn anchored(hir: Hir, case_insensitive: bool) -> Regex {
+    // the flag goes in the pattern itself, so that it is kept when globs are combined into sets
+    let flags = if case_insensitive { "(?i)" } else { "" };
+    Regex::new(&format!("{}^{}$", flags, hir)).unwrap()
+}
+
+/// Look up one of the POSIX character classes (the `digit` in `[[:digit:]]`) by name.
+fn posix_class(name: &[u8]) -> Option<hir::ClassUnicode> {
+    let ranges: &[(char, char)] = match name {
+        b"alnum" => &[('0', '9'), ('A', 'Z'), ('a', 'z')],
+        b"alpha" => &[('A', 'Z'), ('a', 'z')],
+        b"blank" => &[('\t', '\t'), (' ', ' ')],
+        b"cntrl" => &[('\0', '\x1F'), ('\x7F', '\x7F')],
+        b"digit" => &[('0', '9')],
+        b"graph" => &[('!', '~')],
+        b"lower" => &[('a', 'z')],
+        b"print" => &[(' ', '~')],
+        b"punct" => &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+        b"space" => &[('\t', '\r'), (' ', ' ')],
+        b"upper" => &[('A', 'Z')],
+        b"xdigit" => &[('0', '9'), ('A', 'F'), ('a', 'f')],
+        _ => return None,
+    };
+
+    let ranges = ranges
+        .iter()
+        .map(|&(start, end)| hir::ClassUnicodeRange::new(start, end));
+    Some(hir::ClassUnicode::new(ranges))
+}
+
+/// Parse a POSIX character class, such as `[:alpha:]`, from within a bracket expression. Returns
+/// None without consuming anything if the next tokens do not start a POSIX class.
+fn parse_posix_class(tokens: &mut Tokenizer) -> Result<Option<hir::ClassUnicode>> {
+    if tokens.next_token(TokenSet::SQUARE_START).is_none() {
+        return Ok(None);
+    }
+    if tokens.next_token(TokenSet::COLON).is_none() {
+        tokens.reset();
+        return Ok(None);
+    }
+    tokens.flush();
+
+    let class = tokens
+        .read_literal(TokenSet::COLON)
+        .and_then(posix_class)
+        .ok_or_else(|| {
+            tokens.reset();
+            tokens.error(TokenSet::LITERAL)
+        })?;
+
+    tokens
+        .next_token(TokenSet::COLON)
+        .ok_or_else(|| tokens.error(TokenSet::COLON))?;
+    tokens
+        .next_token(TokenSet::SQUARE_END)
+        .ok_or_else(|| tokens.error(TokenSet::SQUARE_END))?;
+    tokens.flush();
+
+    Ok(Some(class))
+}
+
+/// Take a single character from within a bracket expression, removing the backslash from an
+/// escaped character.
+fn charset_letter(tokens: &mut Tokenizer) -> Option<char> {
+    tokens.next_token(TokenSet::ESCAPE);
+    tokens.next_char()
+}
+
+/// Parse a bracket expression, after its opening `[` has been consumed. Supports negation with a
+/// leading `!` or `^`, a literal `]` as the first member, ranges (where a `-` at either end is
+/// taken literally), POSIX character classes and backslash escapes.
+fn parse_charset(tokens: &mut Tokenizer, case_insensitive: bool) -> Result<Hir> {
+    let negated = tokens
+        .next_token(TokenSet::NEGATE | TokenSet::CARET)
+        .is_some();
     let mut class = hir::ClassUnicode::empty();
 
-    let text = tokens
-        .read_literal(TokenSet::SQUARE_END)
-        .ok_or(tokens.error(TokenSet::SQUARE_END))?;
+    if tokens.next_token(TokenSet::SQUARE_END).is_some() {
+        class.push(hir::ClassUnicodeRange::new(']', ']'));
+    }
+    tokens.flush();
+
+    while tokens.next_token(TokenSet::SQUARE_END).is_none() {
+        if let Some(posix) = parse_posix_class(tokens)? {
+            class.union(&posix);
+            continue;
+        }
+
+        let start = charset_letter(tokens)
+            .ok_or_else(|| tokens.error(TokenSet::LITERAL | TokenSet::SQUARE_END))?;
+        tokens.flush();
+
+        let end = match tokens.next_token(TokenSet::DASH) {
+            Some(_) if tokens.next_token(TokenSet::SQUARE_END).is_some() => {
+                // a trailing dash is a literal, so leave it for the next loop iteration
+                tokens.reset();
+                start
+            }
+            Some(_) => {
+                tokens.flush();
+                match charset_letter(tokens) {
+                    Some(end) if end >= start => end,
+                    _ => {
+                        tokens.reset();
+                        return Err(tokens.error(TokenSet::LITERAL));
+                    }
+                }
+            }
+            None => start,
+        };
+        tokens.flush();
+
+        class.push(hir::ClassUnicodeRange::new(start, end));
+    }
 
-    let mut letters = text.chars().peekable();
-    while let Some(letter) = letters.next() {
-        if letters.peek() == Some(&'-') {
-            letters.next();
-            let other_letter = letters.next().ok_or(tokens.error(TokenSet::LITERAL))?;
-            class.push(hir::ClassUnicodeRange::new(letter, other_letter));
-        } else {
-            class.push(hir::ClassUnicodeRange::new(letter, letter));
+    if negated {
+        // the regex only folds case after the class has been negated, which would add the other
+        // case of each negated letter back in
+        if case_insensitive {
+            class.case_fold_simple();
         }
+        class.negate();
+        class.intersect(&not_separator());
+        Ok(class_or_invalid(class))
+    } else {
+        Ok(Hir::class(hir::Class::Unicode(class)))
     }
+}
 
-    let output = Hir::class(hir::Class::Unicode(class));
-    Ok(output)
+/// Parse the patterns in an extended glob's pattern list, after its opening `(` has been
+/// consumed, up to and including the closing `)`. Returns an alternation of the patterns.
+fn parse_list(tokens: &mut Tokenizer, case_insensitive: bool) -> Result<Hir> {
+    let mut alternatives = Vec::new();
+
+    loop {
+        let pieces = parse_sequence(tokens, case_insensitive, true, true)?;
+        let hirs = pieces
+            .into_iter()
+            .map(|piece| match piece {
+                Piece::Hir(hir) => hir,
+                Piece::Not(_) => unreachable!(),
+            })
+            .collect();
+        alternatives.push(Hir::concat(hirs));
+
+        match tokens.next_token(TokenSet::PIPE | TokenSet::PAREN_END) {
+            Some(Token::Pipe) => tokens.flush(),
+            Some(_) => break,
+            None => {
+                return Err(tokens.error(TokenSet::LITERAL | TokenSet::PIPE | TokenSet::PAREN_END))
+            }
+        }
+    }
+
+    Ok(Hir::group(hir::Group {
+        kind: hir::GroupKind::NonCapturing,
+        hir: Box::new(Hir::alternation(alternatives)),
+    }))
 }
 
-fn parse_pattern(tokens: &mut Tokenizer) -> Result<Option<Regex>> {
-    let mut constructor = Vec::new();
+/// Whether a token starts an extended glob's pattern list, when it is followed by a `(`.
+fn starts_list(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Star | Token::Question | Token::At | Token::Plus | Token::Negate
+    )
+}
 
-    let accept_set = TokenSet::STAR | TokenSet::QUESTION | TokenSet::SQUARE_START;
-    let break_set = accept_set | TokenSet::SEPARATOR;
+/// Parse the sequence of patterns that make up a segment, or one of the patterns within an
+/// extended glob's pattern list if nested is set. If extended is set, ksh-style pattern lists
+/// (`?(a|b)`, `*(a|b)`, `+(a|b)`, `@(a|b)` and `!(a|b)`) are supported.
+fn parse_sequence(
+    tokens: &mut Tokenizer,
+    case_insensitive: bool,
+    extended: bool,
+    nested: bool,
+) -> Result<Vec<Piece>> {
+    let mut pieces = Vec::new();
+
+    let mut accept_set =
+        TokenSet::STAR | TokenSet::QUESTION | TokenSet::SQUARE_START | TokenSet::ESCAPE;
+    if extended {
+        accept_set |= TokenSet::AT | TokenSet::PLUS | TokenSet::NEGATE;
+    }
+    let mut break_set = accept_set | TokenSet::SEPARATOR;
+    if nested {
+        break_set |= TokenSet::PIPE | TokenSet::PAREN_END;
+    }
 
     loop {
         match tokens.next_token(accept_set) {
+            Some(token)
+                if extended
+                    && starts_list(&token)
+                    && tokens.next_token(TokenSet::PAREN_START).is_some() =>
+            {
+                if nested {
+                    if let Token::Negate = token {
+                        tokens.reset();
+                        return Err(
+                            tokens.unsupported("!(...) can't be used inside a pattern list")
+                        );
+                    }
+                }
+                tokens.flush();
+                let list = parse_list(tokens, case_insensitive)?;
+                pieces.push(match token {
+                    Token::Star => Piece::Hir(repeat(list, hir::RepetitionKind::ZeroOrMore)),
+                    Token::Question => Piece::Hir(repeat(list, hir::RepetitionKind::ZeroOrOne)),
+                    Token::Plus => Piece::Hir(repeat(list, hir::RepetitionKind::OneOrMore)),
+                    Token::Negate => Piece::Not(list),
+                    _ => Piece::Hir(list),
+                });
+            }
             Some(Token::Star) => {
-                if tokens.next_token(TokenSet::STAR).is_some() {
+                if !nested && tokens.next_token(TokenSet::STAR).is_some() {
                     tokens.reset();
                     break;
                 }
-                constructor.push(star());
+                pieces.push(Piece::Hir(star()));
+            }
+            Some(Token::Question) => pieces.push(Piece::Hir(question())),
+            Some(Token::SquareStart) => {
+                pieces.push(Piece::Hir(parse_charset(tokens, case_insensitive)?))
+            }
+            Some(Token::Escape) => match tokens.next_char() {
+                Some(letter) => {
+                    pieces.push(Piece::Hir(Hir::literal(hir::Literal::Unicode(letter))))
+                }
+                None => {
+                    let byte = tokens
+                        .next_byte()
+                        .ok_or_else(|| tokens.error(TokenSet::LITERAL))?;
+                    pieces.extend(literal(&[byte]).into_iter().map(Piece::Hir));
+                }
+            },
+            // outside of a pattern list, these are plain characters
+            Some(Token::At) => pieces.push(Piece::Hir(Hir::literal(hir::Literal::Unicode('@')))),
+            Some(Token::Plus) => pieces.push(Piece::Hir(Hir::literal(hir::Literal::Unicode('+')))),
+            Some(Token::Negate) => {
+                pieces.push(Piece::Hir(Hir::literal(hir::Literal::Unicode('!'))))
             }
-            Some(Token::Question) => constructor.push(question()),
-            Some(Token::SquareStart) => constructor.push(parse_charset(tokens)?),
             Some(_) => unreachable!(),
             None => match tokens.read_literal(break_set) {
-                Some(literal) => {
-                    let letters = literal
-                        .chars()
-                        .map(|letter| Hir::literal(hir::Literal::Unicode(letter)));
-                    constructor.extend(letters);
-                }
+                Some(bytes) => pieces.extend(literal(bytes).into_iter().map(Piece::Hir)),
                 None => break,
             },
         }
@@ -88,18 +487,45 @@ fn parse_pattern(tokens: &mut Tokenizer) -> Result<Option<Regex>> {
         tokens.flush();
     }
 
-    if constructor.is_empty() {
+    Ok(pieces)
+}
+
+fn parse_pattern(
+    tokens: &mut Tokenizer,
+    case_insensitive: bool,
+    extended: bool,
+) -> Result<Option<Segment>> {
+    let pieces = parse_sequence(tokens, case_insensitive, extended, false)?;
+
+    if pieces.is_empty() {
         Ok(None)
+    } else if pieces.iter().any(|piece| matches!(piece, Piece::Not(_))) {
+        Ok(Some(Segment::Extended(Extended::new(
+            pieces,
+            case_insensitive,
+        ))))
     } else {
-        let total = Hir::concat(constructor);
-        let string = format!("^{}$", total);
-        Ok(Some(Regex::new(&string).unwrap()))
+        let hirs = pieces
+            .into_iter()
+            .map(|piece| match piece {
+                Piece::Hir(hir) => hir,
+                Piece::Not(_) => unreachable!(),
+            })
+            .collect();
+        Ok(Some(Segment::Pattern(anchored(
+            Hir::concat(hirs),
+            case_insensitive,
+        ))))
     }
 }
 
-fn parse_segment(tokens: &mut Tokenizer) -> Result<Option<Segment>> {
-    if let Some(regex) = parse_pattern(tokens)? {
-        return Ok(Some(Segment::Pattern(regex)));
+fn parse_segment(
+    tokens: &mut Tokenizer,
+    case_insensitive: bool,
+    extended: bool,
+) -> Result<Option<Segment>> {
+    if let Some(segment) = parse_pattern(tokens, case_insensitive, extended)? {
+        return Ok(Some(segment));
     }
 
     let output = tokens
@@ -122,13 +548,31 @@ fn parse_segment(tokens: &mut Tokenizer) -> Result<Option<Segment>> {
     Ok(output)
 }
 
-pub fn parse(input: &str) -> Result<Ast> {
+/// Parse a glob into its segments. If case_insensitive is set, the regexes for each segment will
+/// ignore the case of both the pattern and the names matched against it. The glob does not need to
+/// be valid UTF-8.
+pub fn parse(input: impl AsRef<[u8]>, case_insensitive: bool) -> Result<Ast> {
+    parse_with(input.as_ref(), case_insensitive, false)
+}
+
+/// Parse a glob that may use extended glob syntax, as in ksh or bash's extglob option.
+pub fn parse_extended(input: impl AsRef<[u8]>, case_insensitive: bool) -> Result<Ast> {
+    parse_with(input.as_ref(), case_insensitive, true)
+}
+
+fn parse_with(input: &[u8], case_insensitive: bool, extended: bool) -> Result<Ast> {
     let mut tokens = Tokenizer::new(input);
-    let starts_negated = tokens.next_token(TokenSet::NEGATE).is_some();
+    let mut starts_negated = tokens.next_token(TokenSet::NEGATE).is_some();
+    if starts_negated && extended && tokens.next_token(TokenSet::PAREN_START).is_some() {
+        // the glob starts with a !(...) pattern list, rather than being negated
+        starts_negated = false;
+        tokens.reset();
+    }
+    tokens.flush();
 
     let mut segments = Vec::new();
 
-    while let Some(segment) = parse_segment(&mut tokens)? {
+    while let Some(segment) = parse_segment(&mut tokens, case_insensitive, extended)? {
         segments.push(segment);
     }
 
@@ -144,12 +588,12 @@ pub fn parse(input: &str) -> Result<Ast> {
 
 #[cfg(test)]
 mod test {
-    use super::{parse, Segment};
+    use super::{escape_invalid, parse, parse_extended, Segment};
 
     #[test]
     fn single_file() {
-        let glob = parse("filename.txt").unwrap();
-        assert_eq!(false, glob.starts_negated);
+        let glob = parse("filename.txt", false).unwrap();
+        assert!(!glob.starts_negated);
         let regex = match &glob.segments[..] {
             [Segment::Pattern(regex)] => regex,
             other => panic!("Incorrect pattern: {:?}", other),
@@ -159,8 +603,8 @@ mod test {
 
     #[test]
     fn negated_single_file() {
-        let glob = parse("!.gitignore").unwrap();
-        assert_eq!(true, glob.starts_negated);
+        let glob = parse("!.gitignore", false).unwrap();
+        assert!(glob.starts_negated);
         let regex = match &glob.segments[..] {
             [Segment::Pattern(regex)] => regex,
             other => panic!("Incorrect pattern: {:?}", other),
@@ -170,8 +614,8 @@ mod test {
 
     #[test]
     fn regular_path() {
-        let glob = parse("path/to/file.txt").unwrap();
-        assert_eq!(false, glob.starts_negated);
+        let glob = parse("path/to/file.txt", false).unwrap();
+        assert!(!glob.starts_negated);
         let (path, to, file) = match &glob.segments[..] {
             [Segment::Pattern(path), Segment::Separator, Segment::Pattern(to), Segment::Separator, Segment::Pattern(file)] => {
                 (path, to, file)
@@ -185,41 +629,298 @@ mod test {
 
     #[test]
     fn has_question_mark() {
-        let glob = parse("hello.?pp").unwrap();
-        assert_eq!(false, glob.starts_negated);
+        let glob = parse("hello.?pp", false).unwrap();
+        assert!(!glob.starts_negated);
         let regex = match &glob.segments[..] {
             [Segment::Pattern(regex)] => regex,
             other => panic!("Incorrect pattern: {:?}", other),
         };
-        assert!(regex.is_match("hello.cpp"));
-        assert!(regex.is_match("hello.hpp"));
-        assert!(regex.is_match("hello.🚀pp"));
-        assert!(!regex.is_match("hello./pp"));
-        assert!(!regex.is_match("unrelated string"));
+        assert!(regex.is_match(b"hello.cpp"));
+        assert!(regex.is_match(b"hello.hpp"));
+        assert!(regex.is_match("hello.🚀pp".as_bytes()));
+        assert!(!regex.is_match(b"hello./pp"));
+        assert!(!regex.is_match(b"unrelated string"));
     }
 
     #[test]
     fn has_star() {
-        let glob = parse("*.rs").unwrap();
-        assert_eq!(false, glob.starts_negated);
+        let glob = parse("*.rs", false).unwrap();
+        assert!(!glob.starts_negated);
         let regex = match &glob.segments[..] {
             [Segment::Pattern(regex)] => regex,
             other => panic!("Incorrect pattern: {:?}", other),
         };
-        assert!(regex.is_match("main.rs"));
-        assert!(regex.is_match("testing.rs"));
-        assert!(!regex.is_match("path/to/file.rs"));
-        assert!(!regex.is_match("unrelated string"));
+        assert!(regex.is_match(b"main.rs"));
+        assert!(regex.is_match(b"testing.rs"));
+        assert!(!regex.is_match(b"path/to/file.rs"));
+        assert!(!regex.is_match(b"unrelated string"));
     }
 
     #[test]
     fn has_starstar() {
-        let glob = parse("target/**").unwrap();
-        assert_eq!(false, glob.starts_negated);
+        let glob = parse("target/**", false).unwrap();
+        assert!(!glob.starts_negated);
         let regex = match &glob.segments[..] {
             [Segment::Pattern(regex), Segment::Separator, Segment::Anything] => regex,
             other => panic!("Incorrect pattern: {:?}", other),
         };
         assert_eq!("^target$", regex.as_str());
     }
+
+    fn single_regex(pattern: &str) -> regex::bytes::Regex {
+        match &parse(pattern, false).unwrap().segments[..] {
+            [Segment::Pattern(regex)] => regex.clone(),
+            other => panic!("Incorrect pattern: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn has_charset() {
+        let regex = single_regex("file.[ch]");
+        assert!(regex.is_match(b"file.c"));
+        assert!(regex.is_match(b"file.h"));
+        assert!(!regex.is_match(b"file.o"));
+
+        let regex = single_regex("[a-c]x");
+        assert!(regex.is_match(b"bx"));
+        assert!(!regex.is_match(b"dx"));
+    }
+
+    #[test]
+    fn negated_charset() {
+        for pattern in &["[!abc]", "[^abc]"] {
+            let regex = single_regex(pattern);
+            assert!(!regex.is_match(b"a"));
+            assert!(!regex.is_match(b"c"));
+            assert!(regex.is_match(b"d"));
+            assert!(!regex.is_match(b"/"));
+        }
+    }
+
+    #[test]
+    fn charset_literal_brackets_and_dashes() {
+        let regex = single_regex("[]a]");
+        assert!(regex.is_match(b"]"));
+        assert!(regex.is_match(b"a"));
+        assert!(!regex.is_match(b"b"));
+
+        let regex = single_regex("[!]]");
+        assert!(!regex.is_match(b"]"));
+        assert!(regex.is_match(b"a"));
+
+        let regex = single_regex("[-a]");
+        assert!(regex.is_match(b"-"));
+        assert!(regex.is_match(b"a"));
+
+        let regex = single_regex("[a-]");
+        assert!(regex.is_match(b"-"));
+        assert!(regex.is_match(b"a"));
+        assert!(!regex.is_match(b"b"));
+    }
+
+    #[test]
+    fn posix_charset() {
+        let regex = single_regex("[[:digit:]]*");
+        assert!(regex.is_match(b"0abc"));
+        assert!(!regex.is_match(b"abc"));
+
+        let regex = single_regex("[[:upper:][:digit:]_]");
+        assert!(regex.is_match(b"A"));
+        assert!(regex.is_match(b"7"));
+        assert!(regex.is_match(b"_"));
+        assert!(!regex.is_match(b"a"));
+    }
+
+    #[test]
+    fn invalid_charsets() {
+        use crate::error::Error;
+
+        match parse("abc[de", false).unwrap_err() {
+            Error::InvalidGlobParse(_, _, index) => assert_eq!(6, index),
+            other => panic!("Incorrect error: {:?}", other),
+        }
+        match parse("[z-a]", false).unwrap_err() {
+            Error::InvalidGlobParse(_, _, index) => assert_eq!(3, index),
+            other => panic!("Incorrect error: {:?}", other),
+        }
+        match parse("[[:nothing:]]", false).unwrap_err() {
+            Error::InvalidGlobParse(_, _, index) => assert_eq!(3, index),
+            other => panic!("Incorrect error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn escaped_specials() {
+        let regex = single_regex(r"\*.rs");
+        assert!(regex.is_match(b"*.rs"));
+        assert!(!regex.is_match(b"main.rs"));
+
+        let regex = single_regex(r"what\?");
+        assert!(regex.is_match(b"what?"));
+        assert!(!regex.is_match(b"whats"));
+
+        let regex = single_regex(r"\[a]");
+        assert!(regex.is_match(b"[a]"));
+
+        let regex = single_regex(r"[\]\\]");
+        assert!(regex.is_match(b"]"));
+        assert!(regex.is_match(b"\\"));
+    }
+
+    #[test]
+    fn escaped_leading_characters() {
+        let glob = parse(r"\!important", false).unwrap();
+        assert!(!glob.starts_negated);
+        let regex = match &glob.segments[..] {
+            [Segment::Pattern(regex)] => regex,
+            other => panic!("Incorrect pattern: {:?}", other),
+        };
+        assert!(regex.is_match(b"!important"));
+
+        let regex = single_regex(r"\#file");
+        assert!(regex.is_match(b"#file"));
+    }
+
+    #[test]
+    fn trailing_backslash() {
+        parse(r"file\", false).unwrap_err();
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let glob = parse("*.png", true).unwrap();
+        let regex = match &glob.segments[..] {
+            [Segment::Pattern(regex)] => regex,
+            other => panic!("Incorrect pattern: {:?}", other),
+        };
+        assert!(regex.is_match(b"image.png"));
+        assert!(regex.is_match(b"image.PNG"));
+        assert!(regex.is_match(b"image.Png"));
+        assert!(!regex.is_match(b"image.jpg"));
+
+        let glob = parse("[a-c]", true).unwrap();
+        let regex = match &glob.segments[..] {
+            [Segment::Pattern(regex)] => regex,
+            other => panic!("Incorrect pattern: {:?}", other),
+        };
+        assert!(regex.is_match(b"B"));
+
+        for glob in &["[!a]", "[!A]", "[^a-c]", "@([!a])"] {
+            let glob = parse_extended(glob, true).unwrap();
+            let regex = match &glob.segments[..] {
+                [Segment::Pattern(regex)] => regex,
+                other => panic!("Incorrect pattern: {:?}", other),
+            };
+            assert!(!regex.is_match(b"a"));
+            assert!(!regex.is_match(b"A"));
+            assert!(regex.is_match(b"z"));
+        }
+
+        assert!(!single_regex("*.png").is_match(b"image.PNG"));
+    }
+
+    #[test]
+    fn non_utf8() {
+        let is_match =
+            |regex: &regex::bytes::Regex, name: &[u8]| regex.is_match(&escape_invalid(name));
+        let regex = single_regex("*.txt");
+        assert!(is_match(&regex, b"\xFF\xFE.txt"));
+        assert!(!is_match(&regex, b"\xFF/.txt"));
+
+        let regex = single_regex("?");
+        assert!(is_match(&regex, b"\xFF"));
+        assert!(is_match(&regex, "🚀".as_bytes()));
+        assert!(!is_match(&regex, b"\xFF\xFF"));
+
+        let regex = single_regex("[!a]");
+        assert!(is_match(&regex, b"\x80"));
+
+        let glob = parse(b"caf\xE9-*", false).unwrap();
+        let regex = match &glob.segments[..] {
+            [Segment::Pattern(regex)] => regex,
+            other => panic!("Incorrect pattern: {:?}", other),
+        };
+        assert!(is_match(regex, b"caf\xE9-menu"));
+        assert!(!is_match(regex, "café-menu".as_bytes()));
+
+        let glob = parse(b"caf\\\xE9", false).unwrap();
+        let regex = match &glob.segments[..] {
+            [Segment::Pattern(regex)] => regex,
+            other => panic!("Incorrect pattern: {:?}", other),
+        };
+        assert!(is_match(regex, b"caf\xE9"));
+        assert!(!is_match(regex, "café".as_bytes()));
+    }
+
+    #[test]
+    fn readable_errors() {
+        let message = parse("abc[de", false).unwrap_err().to_string();
+        assert_eq!(
+            "Failed to parse glob - expected ']' or a character\n    abc[de\n          ^",
+            message
+        );
+    }
+
+    fn extended_segment(pattern: &str) -> Segment {
+        match parse_extended(pattern, false).unwrap().segments.pop() {
+            Some(segment) => segment,
+            None => panic!("No segments in {}", pattern),
+        }
+    }
+
+    fn extended_match(pattern: &str, name: &str) -> bool {
+        match extended_segment(pattern) {
+            Segment::Pattern(regex) => regex.is_match(name.as_bytes()),
+            Segment::Extended(extended) => {
+                extended.prefilter().is_match(name.as_bytes()) && extended.is_match(name.as_bytes())
+            }
+            other => panic!("Incorrect pattern: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn extended_lists() {
+        assert!(extended_match("*.@(jpg|png)", "photo.png"));
+        assert!(!extended_match("*.@(jpg|png)", "photo.gif"));
+        assert!(extended_match("file?(.bak)", "file"));
+        assert!(extended_match("file?(.bak)", "file.bak"));
+        assert!(!extended_match("file?(.bak)", "file.bak.bak"));
+        assert!(extended_match("a*(xy)b", "ab"));
+        assert!(extended_match("a*(xy)b", "axyxyb"));
+        assert!(!extended_match("a+(xy)b", "ab"));
+        assert!(extended_match("a+(xy)b", "axyb"));
+        assert!(extended_match("@(a|*.[ch])", "main.c"));
+        assert!(extended_match("@(a|+(b|c))", "bcb"));
+        assert!(!extended_match("@(a|b)", "ab"));
+    }
+
+    #[test]
+    fn extended_negation() {
+        assert!(extended_match("!(tests)", "src"));
+        assert!(!extended_match("!(tests)", "tests"));
+        assert!(extended_match("!(tests)", "tests2"));
+        assert!(!extended_match("!(*_test|*_bench).rs", "parser_test.rs"));
+        assert!(!extended_match("!(*_test|*_bench).rs", "parser_bench.rs"));
+        assert!(extended_match("!(*_test|*_bench).rs", "parser.rs"));
+        assert!(extended_match("a!(b)c", "axc"));
+        assert!(!extended_match("a!(b)c", "abc"));
+        assert!(extended_match("!(é)", "e"));
+        assert!(!extended_match("!(é)", "é"));
+
+        // a leading !( is a pattern list, not a negated glob
+        assert!(!parse_extended("!(a)", false).unwrap().starts_negated);
+        assert!(parse_extended("!a", false).unwrap().starts_negated);
+        assert!(parse_extended("!!(a)", false).unwrap().starts_negated);
+    }
+
+    #[test]
+    fn extended_literals() {
+        assert!(extended_match("a+b@c!d", "a+b@c!d"));
+        assert!(extended_match(r"\@(x)", "@(x)"));
+        assert!(extended_match("[(]", "("));
+
+        parse_extended("@(a|b", false).unwrap_err();
+        parse_extended("@(a/b)", false).unwrap_err();
+        parse_extended("@(!(a))", false).unwrap_err();
+    }
 }
@@ -1,8 +1,9 @@
 use crate::error::Error;
+use std::{fmt, str};
 
 bitflags::bitflags! {
     /// A set of possible types of tokens.
-    pub struct TokenSet: u8 {
+    pub struct TokenSet: u16 {
         const NEGATE = 1 << 0;
         const SEPARATOR = 1 << 1;
         const STAR = 1 << 2;
@@ -11,24 +12,79 @@ bitflags::bitflags! {
         const SQUARE_END = 1 << 5;
         const DASH = 1 << 6;
         const LITERAL = 1 << 7;
+        const CARET = 1 << 8;
+        const COLON = 1 << 9;
+        const ESCAPE = 1 << 10;
+        const AT = 1 << 11;
+        const PLUS = 1 << 12;
+        const PAREN_START = 1 << 13;
+        const PAREN_END = 1 << 14;
+        const PIPE = 1 << 15;
     }
 }
 
 impl TokenSet {
-    fn test_char(self, target: char) -> Option<Token> {
+    fn test_char(self, target: u8) -> Option<Token> {
         match target {
-            '!' if self.contains(TokenSet::NEGATE) => Some(Token::Negate),
-            '/' if self.contains(TokenSet::SEPARATOR) => Some(Token::Separator),
-            '*' if self.contains(TokenSet::STAR) => Some(Token::Star),
-            '?' if self.contains(TokenSet::QUESTION) => Some(Token::Question),
-            '[' if self.contains(TokenSet::SQUARE_START) => Some(Token::SquareStart),
-            ']' if self.contains(TokenSet::SQUARE_END) => Some(Token::SquareEnd),
-            '-' if self.contains(TokenSet::DASH) => Some(Token::Dash),
+            b'!' if self.contains(TokenSet::NEGATE) => Some(Token::Negate),
+            b'/' if self.contains(TokenSet::SEPARATOR) => Some(Token::Separator),
+            b'*' if self.contains(TokenSet::STAR) => Some(Token::Star),
+            b'?' if self.contains(TokenSet::QUESTION) => Some(Token::Question),
+            b'[' if self.contains(TokenSet::SQUARE_START) => Some(Token::SquareStart),
+            b']' if self.contains(TokenSet::SQUARE_END) => Some(Token::SquareEnd),
+            b'-' if self.contains(TokenSet::DASH) => Some(Token::Dash),
+            b'^' if self.contains(TokenSet::CARET) => Some(Token::Caret),
+            b':' if self.contains(TokenSet::COLON) => Some(Token::Colon),
+            b'\\' if self.contains(TokenSet::ESCAPE) => Some(Token::Escape),
+            b'@' if self.contains(TokenSet::AT) => Some(Token::At),
+            b'+' if self.contains(TokenSet::PLUS) => Some(Token::Plus),
+            b'(' if self.contains(TokenSet::PAREN_START) => Some(Token::ParenStart),
+            b')' if self.contains(TokenSet::PAREN_END) => Some(Token::ParenEnd),
+            b'|' if self.contains(TokenSet::PIPE) => Some(Token::Pipe),
             _ => None,
         }
     }
 }
 
+impl fmt::Display for TokenSet {
+    /// Describe the tokens in the set, for use in error messages.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "the end of the pattern");
+        }
+
+        let names = [
+            (TokenSet::NEGATE, "'!'"),
+            (TokenSet::SEPARATOR, "'/'"),
+            (TokenSet::STAR, "'*'"),
+            (TokenSet::QUESTION, "'?'"),
+            (TokenSet::SQUARE_START, "'['"),
+            (TokenSet::SQUARE_END, "']'"),
+            (TokenSet::DASH, "'-'"),
+            (TokenSet::CARET, "'^'"),
+            (TokenSet::COLON, "':'"),
+            (TokenSet::ESCAPE, "'\\'"),
+            (TokenSet::AT, "'@'"),
+            (TokenSet::PLUS, "'+'"),
+            (TokenSet::PAREN_START, "'('"),
+            (TokenSet::PAREN_END, "')'"),
+            (TokenSet::PIPE, "'|'"),
+            (TokenSet::LITERAL, "a character"),
+        ];
+        let names = names
+            .iter()
+            .filter(|(token, _)| self.contains(*token))
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+
+        match names.split_last() {
+            Some((last, [])) => write!(f, "{}", last),
+            Some((last, rest)) => write!(f, "{} or {}", rest.join(", "), last),
+            None => unreachable!(),
+        }
+    }
+}
+
 /// A token pulled from the parser.
 pub enum Token {
     Ending,
@@ -39,17 +95,25 @@ pub enum Token {
     SquareStart,
     SquareEnd,
     Dash,
+    Caret,
+    Colon,
+    Escape,
+    At,
+    Plus,
+    ParenStart,
+    ParenEnd,
+    Pipe,
 }
 
 pub struct Tokenizer<'a> {
-    inner: &'a str,
+    inner: &'a [u8],
     index: usize,
     last_index: usize,
 }
 
 impl<'a> Tokenizer<'a> {
     /// Create a new tokenizer
-    pub fn new(inner: &'a str) -> Self {
+    pub fn new(inner: &'a [u8]) -> Self {
         Tokenizer {
             inner,
             index: 0,
@@ -57,7 +121,7 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
-    fn remaining(&self) -> &'a str {
+    fn remaining(&self) -> &'a [u8] {
         &self.inner[self.index..]
     }
 
@@ -75,7 +139,7 @@ impl<'a> Tokenizer<'a> {
         let remaining = self.remaining();
 
         if accepted == TokenSet::empty() {
-            if remaining == "" {
+            if remaining.is_empty() {
                 return Some(Token::Ending);
             } else {
                 return None;
@@ -83,9 +147,8 @@ impl<'a> Tokenizer<'a> {
         }
 
         let output = remaining
-            .chars()
-            .next()
-            .and_then(|letter| accepted.test_char(letter));
+            .first()
+            .and_then(|&letter| accepted.test_char(letter));
 
         if output.is_some() {
             self.index += 1;
@@ -94,34 +157,63 @@ impl<'a> Tokenizer<'a> {
         output
     }
 
+    /// Take a single character from the target, regardless of whether it would otherwise be a
+    /// token. Returns None, without consuming anything, if the target does not start with a valid
+    /// UTF-8 character.
+    pub fn next_char(&mut self) -> Option<char> {
+        let remaining = self.remaining();
+        let start = &remaining[..remaining.len().min(4)];
+        let valid = match str::from_utf8(start) {
+            Ok(valid) => valid,
+            Err(error) => str::from_utf8(&start[..error.valid_up_to()]).unwrap(),
+        };
+
+        let output = valid.chars().next();
+        if let Some(letter) = output {
+            self.index += letter.len_utf8();
+        }
+
+        output
+    }
+
+    /// Take a single byte from the target, regardless of whether it would otherwise be a token.
+    pub fn next_byte(&mut self) -> Option<u8> {
+        let output = self.remaining().first().cloned();
+        if output.is_some() {
+            self.index += 1;
+        }
+
+        output
+    }
+
     /// Take a string literal from the target, that is terminated by any one of the tokens in the
     /// follow set.
-    pub fn read_literal(&mut self, follow: TokenSet) -> Option<&'a str> {
-        let index = self
-            .remaining()
-            .find(|letter| follow.test_char(letter).is_some());
-
-        if let Some(index) = index {
-            let (start, _remaining) = self.remaining().split_at(index);
-            if start == "" {
-                None
-            } else {
-                self.index += index;
-                Some(start)
-            }
+    pub fn read_literal(&mut self, follow: TokenSet) -> Option<&'a [u8]> {
+        let remaining = self.remaining();
+        let length = remaining
+            .iter()
+            .position(|&letter| follow.test_char(letter).is_some())
+            .unwrap_or(remaining.len());
+
+        if length == 0 {
+            None
         } else {
-            let rest = self.remaining();
-            self.index = self.inner.len();
-            if rest == "" {
-                None
-            } else {
-                Some(rest)
-            }
+            self.index += length;
+            Some(&remaining[..length])
         }
     }
 
+    /// Create an error for syntax that is recognised, but can't be used where it was found.
+    pub fn unsupported(&self, reason: &'static str) -> Error {
+        Error::InvalidGlobCompile(String::from_utf8_lossy(self.inner).into_owned(), reason)
+    }
+
     /// Create an error message from the current position.
     pub fn error(&self, token_set: TokenSet) -> Error {
-        Error::InvalidGlobParse(self.inner.to_string(), token_set, self.index)
+        Error::InvalidGlobParse(
+            String::from_utf8_lossy(self.inner).into_owned(),
+            token_set,
+            self.index,
+        )
     }
 }
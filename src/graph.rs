@@ -1,54 +1,77 @@
-use std::{cmp, iter};
+use std::{fmt::Debug, iter, mem};
 
 use either::Either;
-use slab::Slab;
+
+/// Identifies a node of a graph. Nodes live in numbered slots, which can be reused by later nodes,
+/// so a key also holds the generation of its slot. Newer keys for a slot have higher generations.
+pub trait NodeKey: Copy + Eq + Debug {
+    fn index(self) -> usize;
+    fn generation(self) -> u32;
+}
 
 #[derive(Debug)]
-pub struct Edge<'a, W> {
+pub struct Edge<'a, K, W> {
     pub weight: &'a W,
-    pub connects_to: usize,
+    pub connects_to: K,
     // ensure that nobody creates arbitrary edges
     _seal: (),
 }
 
 #[derive(Debug)]
-struct InnerEdge {
-    weight: usize,
-    connects_to: usize,
+struct InnerEdge<K, W> {
+    weight: W,
+    connects_to: K,
 }
 
 #[derive(Debug)]
-struct Node {
-    incoming: Vec<InnerEdge>,
-    outgoing: Vec<InnerEdge>,
+struct Node<K, W> {
+    generation: u32,
+    incoming: Vec<InnerEdge<K, W>>,
+    outgoing: Vec<InnerEdge<K, W>>,
 }
 
-impl Node {
+impl<K, W> Node<K, W> {
     /// Create a new node.
     pub fn new() -> Self {
         let incoming = Vec::new();
         let outgoing = Vec::new();
 
-        Node { incoming, outgoing }
+        Node {
+            generation: 0,
+            incoming,
+            outgoing,
+        }
     }
 }
 
-/// A directed graph with weighted edges, backed by two-way adjacency lists. Nodes are usizes, and
-/// store no data. As such, there is no method to create a node. Instead, nodes will be initialised
-/// (with no edges) where necessary.
+/// Remove one edge from an adjacency list, returning its weight.
+fn take_edge<K, W: Copy>(
+    edges: &mut Vec<InnerEdge<K, W>>,
+    predicate: impl Fn(&InnerEdge<K, W>) -> bool,
+) -> Option<W> {
+    let index = edges.iter().position(predicate)?;
+    Some(edges.swap_remove(index).weight)
+}
+
+/// A directed graph with weighted edges, backed by two-way adjacency lists. Nodes are keys of
+/// values stored elsewhere, and store no data. As such, there is no method to create a node.
+/// Instead, nodes will be initialised (with no edges) where necessary. A node with no edges left
+/// can be taken over by a newer key for the same slot, after which the older key is stale and has
+/// no edges. The graph can't tell whether a key is still live where it came from, so that has to be
+/// checked before adding edges to it.
+/// Weights are small values that are copied into both ends of an edge, and edges with the same
+/// ends and weight are interchangeable.
 #[derive(Debug)]
-pub struct Graph<W> {
-    weights: Slab<W>,
-    nodes: Vec<Node>,
+pub struct Graph<K, W> {
+    nodes: Vec<Node<K, W>>,
 }
 
-impl<W> Graph<W> {
+impl<K: NodeKey, W: Copy + PartialEq> Graph<K, W> {
     /// Create a new, empty graph.
     pub fn new() -> Self {
-        let weights = Slab::new();
         let nodes = Vec::new();
 
-        Graph { weights, nodes }
+        Graph { nodes }
     }
 
     /// Ensure there is space in the graph for an nth node, by adding new empty nodes up to n if
@@ -58,27 +81,55 @@ impl<W> Graph<W> {
             .extend(iter::repeat_with(Node::new).take((1 + n).saturating_sub(self.nodes.len())));
     }
 
-    /// Create an edge. Takes the indices of the nodes to connect, as well as the weight to connect
-    /// them with.
-    pub fn add_edge(&mut self, from: usize, to: usize, weight: W) {
-        self.extend_to(cmp::max(from, to));
-        let weight = self.weights.insert(weight);
+    /// Look up the node for a key, unless the key is stale.
+    fn node(&self, key: K) -> Option<&Node<K, W>> {
+        self.nodes
+            .get(key.index())
+            .filter(|node| node.generation == key.generation())
+    }
+
+    fn node_mut(&mut self, key: K) -> Option<&mut Node<K, W>> {
+        self.nodes
+            .get_mut(key.index())
+            .filter(|node| node.generation == key.generation())
+    }
 
-        self.nodes[from].outgoing.push(InnerEdge {
+    /// Look up the node for a key, initialising it if it's new. Panics if a newer key has used the
+    /// slot since, since an edge to the key would alias whatever the slot holds now.
+    fn claim(&mut self, key: K) -> &mut Node<K, W> {
+        self.extend_to(key.index());
+        let node = &mut self.nodes[key.index()];
+        let is_empty = node.incoming.is_empty() && node.outgoing.is_empty();
+        if is_empty && node.generation < key.generation() {
+            node.generation = key.generation();
+        }
+        assert_eq!(
+            node.generation,
+            key.generation(),
+            "Attempt to add an edge to stale node {:?}",
+            key
+        );
+        node
+    }
+
+    /// Create an edge. Takes the keys of the nodes to connect, as well as the weight to connect
+    /// them with.
+    pub fn add_edge(&mut self, from: K, to: K, weight: W) {
+        self.claim(from).outgoing.push(InnerEdge {
             weight,
             connects_to: to,
         });
-        self.nodes[to].incoming.push(InnerEdge {
+        self.claim(to).incoming.push(InnerEdge {
             weight,
             connects_to: from,
         });
     }
 
     /// Iterate over the edges leaving a node.
-    pub fn outgoing(&self, node: usize) -> impl Iterator<Item = Edge<W>> {
-        match self.nodes.get(node) {
-            Some(node) => Either::Left(node.outgoing.iter().map(move |edge| Edge {
-                weight: &self.weights[edge.weight],
+    pub fn outgoing(&self, node: K) -> impl Iterator<Item = Edge<'_, K, W>> {
+        match self.node(node) {
+            Some(node) => Either::Left(node.outgoing.iter().map(|edge| Edge {
+                weight: &edge.weight,
                 connects_to: edge.connects_to,
                 _seal: (),
             })),
@@ -87,14 +138,117 @@ impl<W> Graph<W> {
     }
 
     /// Iterate over the edges entering a node.
-    pub fn incoming(&self, node: usize) -> impl Iterator<Item = Edge<W>> {
-        match self.nodes.get(node) {
-            Some(node) => Either::Left(node.incoming.iter().map(move |edge| Edge {
-                weight: &self.weights[edge.weight],
+    pub fn incoming(&self, node: K) -> impl Iterator<Item = Edge<'_, K, W>> {
+        match self.node(node) {
+            Some(node) => Either::Left(node.incoming.iter().map(|edge| Edge {
+                weight: &edge.weight,
                 connects_to: edge.connects_to,
                 _seal: (),
             })),
             None => Either::Right(iter::empty()),
         }
     }
+
+    /// Remove the first edge from one node to another whose weight satisfies the predicate.
+    /// Returns the weight of the removed edge.
+    pub fn remove_edge(&mut self, from: K, to: K, predicate: impl Fn(&W) -> bool) -> Option<W> {
+        let outgoing = &mut self.node_mut(from)?.outgoing;
+        let weight = take_edge(outgoing, |edge| {
+            edge.connects_to == to && predicate(&edge.weight)
+        })?;
+
+        let incoming = &mut self.nodes[to.index()].incoming;
+        take_edge(incoming, |edge| {
+            edge.connects_to == from && edge.weight == weight
+        })
+        .expect("Edge missing from incoming list");
+
+        Some(weight)
+    }
+
+    /// Remove every edge entering or leaving a node, returning the weights of the edges that were
+    /// removed.
+    pub fn remove_node(&mut self, node: K) -> Vec<W> {
+        let (incoming, outgoing) = match self.node_mut(node) {
+            Some(Node {
+                incoming, outgoing, ..
+            }) => (mem::take(incoming), mem::take(outgoing)),
+            None => return Vec::new(),
+        };
+
+        let mut weights = Vec::with_capacity(incoming.len() + outgoing.len());
+        for edge in incoming {
+            // self loops appear in both lists, which have both been taken already
+            take_edge(
+                &mut self.nodes[edge.connects_to.index()].outgoing,
+                |other| other.connects_to == node && other.weight == edge.weight,
+            );
+            weights.push(edge.weight);
+        }
+        for edge in outgoing {
+            take_edge(
+                &mut self.nodes[edge.connects_to.index()].incoming,
+                |other| other.connects_to == node && other.weight == edge.weight,
+            );
+            // self loops were already counted with the incoming edges
+            if edge.connects_to != node {
+                weights.push(edge.weight);
+            }
+        }
+
+        weights
+    }
+
+    /// An estimate of the memory used by the graph, in bytes.
+    pub fn memory(&self) -> usize {
+        let edges = self
+            .nodes
+            .iter()
+            .map(|node| node.incoming.capacity() + node.outgoing.capacity())
+            .sum::<usize>();
+        self.nodes.capacity() * mem::size_of::<Node<K, W>>()
+            + edges * mem::size_of::<InnerEdge<K, W>>()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Graph, NodeKey};
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    struct TestKey(usize, u32);
+
+    impl NodeKey for TestKey {
+        fn index(self) -> usize {
+            self.0
+        }
+
+        fn generation(self) -> u32 {
+            self.1
+        }
+    }
+
+    #[test]
+    fn reused_slots() {
+        let mut graph = Graph::new();
+        let (parent, old, new) = (TestKey(0, 0), TestKey(1, 0), TestKey(1, 1));
+        graph.add_edge(parent, old, 'a');
+        assert_eq!(vec!['a'], graph.remove_node(old));
+
+        // the empty slot is taken over by the newer key, and the older key has no edges
+        graph.add_edge(parent, new, 'b');
+        assert_eq!(1, graph.incoming(new).count());
+        assert_eq!(0, graph.incoming(old).count());
+        assert!(graph.remove_edge(parent, old, |_| true).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "stale node")]
+    fn stale_key_in_empty_slot() {
+        let mut graph = Graph::new();
+        let (parent, old, new) = (TestKey(0, 0), TestKey(1, 0), TestKey(1, 1));
+        graph.add_edge(parent, new, 'a');
+        graph.remove_node(new);
+        graph.add_edge(parent, old, 'b');
+    }
 }
@@ -1,6 +1,10 @@
 pub use self::{
-    error::{Error, Result},
-    tree::Tree,
+    error::{Error, Operation, Result},
+    glob::{Pattern, PatternOptions},
+    tree::{
+        CancelToken, Event, Explanation, Gitignore, IgnoreSource, MemoryStats, Progress,
+        ProgressCallback, Tree, TreeOptions,
+    },
 };
 
 mod error;
@@ -1,112 +1,666 @@
 use self::{
+    crawl::{Found, Scanned},
     ignore::Ignore,
-    store::{TreeEntry, TreeStore},
+    include::Include,
+    names::{Name, NameArena},
+    store::{Key, TreeEntry, TreeStore},
 };
 use crate::{
+    error::{Error, Operation},
     fs::{File, FileType},
     graph::Graph,
     Result,
 };
-use std::collections::HashMap;
 use std::{
-    ffi::{CString, OsStr},
+    collections::{HashMap, HashSet},
+    ffi::{CStr, CString, OsStr, OsString},
     fmt::{Debug, Formatter},
-    os::unix::ffi::OsStrExt,
+    io::{self, ErrorKind, Read},
+    mem,
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
     path::{Component, Path, PathBuf},
+    thread,
 };
 
+pub use self::{
+    ignore::{Explanation, Gitignore, IgnoreSource},
+    progress::{CancelToken, Progress, ProgressCallback},
+};
+
+mod crawl;
 mod ignore;
+mod include;
+mod names;
+mod progress;
 mod store;
 
 /// How one node in the tree is connected to another node in the tree.
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum Connection {
     /// This node is a directory, and the connected node is one of it's entries.
-    Child(CString),
+    Child(Name),
     /// This node is a symbolic link, and the connected node is what it links to.
     SymLink,
 }
 
+/// A change to the set of files that a tree tracks.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Event {
+    /// A file was added to the tree, along with everything beneath it.
+    Added(PathBuf),
+    /// A file was removed from the tree, along with everything beneath it.
+    Removed(PathBuf),
+}
+
+/// Settings that control how a tree is crawled.
+#[derive(Debug, Clone, Default)]
+pub struct TreeOptions {
+    /// Match the patterns in ignore files regardless of case, like git's `core.ignorecase`.
+    pub ignore_case: bool,
+    /// If not empty, only track the paths that match one of these globs (as well as the
+    /// directories leading to them). The globs use the same syntax as lines of a gitignore file,
+    /// and are relative to the root. Ignore files still apply to the paths that they match.
+    pub include: Vec<OsString>,
+    /// The most files that the tree may keep open at once. Only directories are kept open while
+    /// crawling, and directories that would go over the budget are skipped and recorded as errors.
+    /// Ignore files are opened within the same budget while they're read, so a tree that is using
+    /// all of it closes cached files to read them, or fails if there are none.
+    /// If this isn't set, the tree is limited only by the process's limit on open files, which is
+    /// raised as far as the system allows.
+    pub max_open_files: Option<usize>,
+    /// How many files to keep open after they've been read with `Tree::read_file`, so that reading
+    /// them again doesn't need to reopen them. The least recently read files are closed first.
+    pub file_cache: usize,
+    /// The number of threads to scan directories with while crawling. If this is zero, one thread
+    /// is used for each CPU. With one thread, the tree is crawled on the calling thread.
+    pub threads: usize,
+    /// Called with the progress of the crawl after each directory is scanned, both while building
+    /// the tree and while crawling directories that are no longer ignored.
+    pub progress: Option<ProgressCallback>,
+    /// Stops crawling once it's cancelled, making the crawl fail with `Error::Cancelled` if any
+    /// directories were left unscanned. The token is cleared each time the tree starts a refresh,
+    /// so the same token can cancel each refresh in turn - see `Tree::reload_ignore`.
+    pub cancel: CancelToken,
+}
+
+/// An estimate of the memory that a tree is using, in bytes. Only the memory held by the tree's
+/// own storage is counted, not the memory held by the allocator, or by the compiled ignore files.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct MemoryStats {
+    /// The number of files and directories in the tree.
+    pub entries: usize,
+    /// The entries themselves, and the indices used to look them up by fd or inode.
+    pub storage: usize,
+    /// The connections between entries, and the index of each directory's entries by name.
+    pub structure: usize,
+    /// The names of directory entries, which are stored once for each distinct name.
+    pub names: usize,
+}
+
+impl MemoryStats {
+    /// The total memory used by the tree, in bytes.
+    pub fn total(&self) -> usize {
+        self.storage + self.structure + self.names
+    }
+}
+
 /// An in-memory wrapper around a directory tree.
 pub struct Tree {
     root_dir: PathBuf,
-    root_entry: usize,
+    root_entry: Key,
     storage: TreeStore,
-    structure: Graph<Connection>,
+    structure: Graph<Key, Connection>,
+    // the entries of each directory, by name, so that paths can be looked up without scanning
+    // through every edge in the graph
+    children: HashMap<Key, HashMap<Name, Key>>,
+    names: NameArena,
     ignores: Ignore,
+    includes: Include,
+    max_open_files: Option<usize>,
+    threads: usize,
+    on_progress: Option<ProgressCallback>,
+    progress: Progress,
+    cancel: CancelToken,
+    // the paths that couldn't be added to the tree, and why
+    errors: Vec<(PathBuf, Error)>,
 }
 
 impl Tree {
-    /// Open up a path, and create a tree at that location.
+    /// Open up a path, and create a tree at that location using the default options. This crawls
+    /// the tree on one thread for each CPU - set `TreeOptions::threads` to 1 to crawl it on the
+    /// calling thread alone.
     pub fn new(root: impl AsRef<Path>) -> Result<Self> {
+        Self::with_options(root, TreeOptions::default())
+    }
+
+    /// Open up a path, and create a tree at that location using the provided options.
+    pub fn with_options(root: impl AsRef<Path>, options: TreeOptions) -> Result<Self> {
         let root_dir = root.as_ref().canonicalize()?;
 
         let mut output = Tree {
             root_dir,
-            // fix this up soon, leave it as max_value so it's obvious there's an error in case it
-            // isn't fixed up
-            root_entry: usize::max_value(),
-            storage: TreeStore::new(),
+            // fix this up soon, leave it dangling so it's obvious there's an error in case it isn't
+            // fixed up
+            root_entry: Key::DANGLING,
+            storage: TreeStore::new(options.file_cache),
             structure: Graph::new(),
-            ignores: Ignore::new(),
+            children: HashMap::new(),
+            names: NameArena::new(),
+            ignores: Ignore::new(options.ignore_case),
+            includes: Include::new(&options.include, options.ignore_case)?,
+            max_open_files: options.max_open_files,
+            threads: match options.threads {
+                0 => thread::available_parallelism().map_or(1, usize::from),
+                threads => threads,
+            },
+            on_progress: options.progress,
+            progress: Progress::default(),
+            cancel: options.cancel,
+            errors: Vec::new(),
         };
 
-        let (file_type, root_entry) = {
-            let fd = {
-                let path = CString::new(output.root_dir.as_os_str().as_bytes())
-                    .expect("Canonicalized path contains nul byte");
-                File::open(&path)?
-            };
-            let (file_type, inode) = fd.stat()?;
-            (file_type, TreeEntry::new(fd, inode))
+        let fd = {
+            let path = CString::new(output.root_dir.as_os_str().as_bytes())
+                .expect("Canonicalized path contains nul byte");
+            File::open(&path)?
         };
-        let mut unresolved_files = Vec::new();
+        let (file_type, id) = fd.stat().map_err(|error| error.in_dir(&output.root_dir))?;
+        // the root can't be left out, so it has to be scanned successfully
+        let scanned = match file_type {
+            FileType::Directory => {
+                Some(Scanned::scan(&fd).map_err(|error| error.in_dir(&output.root_dir))?)
+            }
+            _ => None,
+        };
+
+        output.root_entry = output
+            .storage
+            .insert(TreeEntry::new(Some(fd), id, file_type))?;
+        output.includes.open_root(output.root_entry);
         let mut unresolved_symlinks = Vec::new();
+        let new_dirs = match scanned {
+            Some(scanned) => {
+                output.merge_scanned(output.root_entry, Ok(scanned), &mut unresolved_symlinks)
+            }
+            None => Vec::new(),
+        };
+        // a cancel that comes too late to skip any directories doesn't fail the crawl
+        if !output.crawl(new_dirs, unresolved_symlinks) {
+            return Err(Error::Cancelled);
+        }
 
-        output.root_entry = output.add_file(root_entry, file_type, &mut unresolved_files)?;
+        Ok(output)
+    }
 
-        while let Some(action) = unresolved_files.pop() {
-            output.add_child_file(
-                action.key,
-                action.path,
-                &mut unresolved_files,
-                &mut unresolved_symlinks,
-            )?;
+    /// Crawl down through the tree, scanning the given directories and then every directory beneath
+    /// them, and then link up any symlinks that were found along the way. Returns false if the crawl
+    /// was cancelled before every directory was scanned, in which case the symlinks found before
+    /// then are still linked up.
+    fn crawl(
+        &mut self,
+        mut new_dirs: Vec<Key>,
+        mut unresolved_symlinks: Vec<UnresolvedSymlink>,
+    ) -> bool {
+        let mut finished = true;
+        if self.threads > 1 {
+            let fd = |tree: &Self, key| (key, tree.dir_fd(key).as_raw_fd());
+            let start = new_dirs.iter().map(|&key| fd(self, key)).collect();
+            // entries are only added while crawling, so the fds of the directories being scanned
+            // stay open
+            crawl::scan_parallel(self.threads, start, |dir, scanned| {
+                if self.cancel.is_cancelled() {
+                    finished = false;
+                    return None;
+                }
+                let new_dirs = self.merge_scanned(dir, scanned, &mut unresolved_symlinks);
+                Some(new_dirs.into_iter().map(|key| fd(self, key)).collect())
+            });
+        } else {
+            while let Some(dir) = new_dirs.pop() {
+                if self.cancel.is_cancelled() {
+                    finished = false;
+                    break;
+                }
+                let scanned = Scanned::scan(self.dir_fd(dir));
+                let added = self.merge_scanned(dir, scanned, &mut unresolved_symlinks);
+                new_dirs.extend(added);
+            }
         }
 
         for UnresolvedSymlink { key, path } in unresolved_symlinks {
-            let parent_key = if let Some(edge) = output
+            let parent_key =
+                if let Some(edge) = self.structure.incoming(key).find(|edge| {
+                    matches!(edge.weight, Connection::Child(_)) && edge.connects_to != key
+                }) {
+                    edge.connects_to
+                } else {
+                    log::warn!("Symlink found without parent directory");
+                    continue;
+                };
+            let path = Path::new(OsStr::from_bytes(path.as_bytes()));
+            if let Some(target_key) = self.follow_path(parent_key, path) {
+                self.connect(key, target_key, Connection::SymLink);
+            }
+        }
+        finished
+    }
+
+    /// Read an ignore file again, after it has been created, changed or deleted, and update the
+    /// tree to match. Files that are now ignored are removed from the tree, and files that are no
+    /// longer ignored are crawled. Returns an event for the top of each subtree that was added or
+    /// removed. The path may be relative to the root, or an absolute path beneath it.
+    ///
+    /// The tree's cancel token is cleared when the refresh starts. If it's cancelled during the
+    /// refresh, the refresh stops early and returns the events for the changes that were made
+    /// before then, which the tree keeps. Subtrees that were added may be only partly crawled, and
+    /// the token is left cancelled so that the caller can tell.
+    pub fn reload_ignore(&mut self, path: impl AsRef<Path>) -> Result<Vec<Event>> {
+        let path = path.as_ref();
+        let relative = path.strip_prefix(&self.root_dir).unwrap_or(path);
+        let mut events = Vec::new();
+
+        if relative.file_name().map(OsStr::as_bytes) != Some(b".gitignore") {
+            return Ok(events);
+        }
+        // ignore files in directories that aren't part of the tree don't affect it
+        let dir = match relative.parent().and_then(|parent| self.lookup(parent)) {
+            Some(dir) => dir,
+            None => return Ok(events),
+        };
+
+        let name = CString::new(".gitignore").unwrap();
+        self.reserve_fd()?;
+        match self.dir_fd(dir).open_at(&name) {
+            Ok(fd) => self.read_gitignore(dir, fd)?,
+            Err(error) if error.io_error().map(io::Error::kind) == Some(ErrorKind::NotFound) => {
+                self.read_gitignore(dir, io::empty())?
+            }
+            Err(error) => return Err(error.in_dir(self.path_to(dir))),
+        }
+
+        self.refresh(dir, &mut events)?;
+        Ok(events)
+    }
+
+    /// Check every directory at or beneath dir against the current ignore rules, removing any
+    /// entries that are now ignored or no longer exist, and crawling any entries that are no longer
+    /// ignored. Paths beneath dir that couldn't be added before are tried again.
+    fn refresh(&mut self, dir: Key, events: &mut Vec<Event>) -> Result<()> {
+        let dir_path = self.path_to(dir);
+        self.progress = Progress::default();
+        self.cancel.reset();
+        // ignore files that aren't read again keep their errors
+        self.errors.retain(|(path, error)| {
+            !path.starts_with(&dir_path) || matches!(error, Error::InIgnoreFile(..))
+        });
+
+        let mut new_dirs = Vec::new();
+        let mut unresolved_symlinks = Vec::new();
+        let mut dirs = vec![dir];
+
+        while let Some(dir) = dirs.pop() {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            let entries = self
+                .dir_fd(dir)
+                .scan()
+                .map_err(|error| error.in_dir(self.path_to(dir)))?;
+            let names = entries
+                .iter()
+                .map(|entry| entry.name.clone())
+                .collect::<HashSet<_>>();
+            let children = self
+                .structure
+                .outgoing(dir)
+                .filter_map(|edge| match edge.weight {
+                    Connection::Child(name) => {
+                        Some((self.names.get(*name).to_owned(), edge.connects_to))
+                    }
+                    Connection::SymLink => None,
+                })
+                .collect::<Vec<_>>();
+
+            let mut existing = HashSet::new();
+            for (name, child) in children {
+                let is_dir =
+                    self.storage.key_to_entry(child).unwrap().file_type() == FileType::Directory;
+                let os_name = OsStr::from_bytes(name.as_bytes());
+
+                if !names.contains(&name) || !self.should_track(dir, os_name, is_dir) {
+                    events.push(Event::Removed(self.path_to(child)));
+                    self.remove_child(dir, &name, child);
+                    continue;
+                }
+                if is_dir {
+                    self.ignores.open_at(dir, os_name, child);
+                    self.includes.open_at(dir, os_name, child);
+                    dirs.push(child);
+                }
+                existing.insert(name);
+            }
+
+            let added = entries
+                .into_iter()
+                .filter(|entry| !existing.contains(&entry.name))
+                .collect();
+            let found = Found::find_all(self.dir_fd(dir), added)
+                .map_err(|error| error.in_dir(self.path_to(dir)))?;
+            for (name, found) in found {
+                self.add_child_file(dir, &name, found, &mut new_dirs, &mut unresolved_symlinks);
+                if let Some(child) = self.child(dir, OsStr::from_bytes(name.as_bytes())) {
+                    events.push(Event::Added(self.path_to(child)));
+                }
+            }
+        }
+
+        self.crawl(new_dirs, unresolved_symlinks);
+        Ok(())
+    }
+
+    /// Remove an entry from a directory. Unless it is also an entry of another directory, it is
+    /// then removed from the tree along with everything beneath it.
+    fn remove_child(&mut self, parent: Key, name: &CStr, child: Key) {
+        let child_path = self
+            .path_to(parent)
+            .join(OsStr::from_bytes(name.to_bytes()));
+        self.errors
+            .retain(|(path, _)| !path.starts_with(&child_path));
+        let name = match self.names.find(name.to_bytes()) {
+            Some(name) => name,
+            None => return,
+        };
+        let connection = Connection::Child(name);
+        if self
+            .structure
+            .remove_edge(parent, child, |other| *other == connection)
+            .is_some()
+        {
+            self.names.release(name);
+        }
+        if let Some(children) = self.children.get_mut(&parent) {
+            children.remove(&name);
+        }
+
+        let mut stack = vec![child];
+        while let Some(key) = stack.pop() {
+            let is_linked = self
                 .structure
                 .incoming(key)
-                .find(|edge| edge.connects_to != key)
-            {
-                edge.connects_to
-            } else {
-                log::warn!("Symlink found without parent directory");
+                .any(|edge| matches!(edge.weight, Connection::Child(_)));
+            if is_linked {
                 continue;
+            }
+
+            stack.extend(
+                self.structure
+                    .outgoing(key)
+                    .filter(|edge| matches!(edge.weight, Connection::Child(_)))
+                    .map(|edge| edge.connects_to),
+            );
+            for connection in self.structure.remove_node(key) {
+                if let Connection::Child(name) = connection {
+                    self.names.release(name);
+                }
+            }
+            self.children.remove(&key);
+            self.storage.remove(key);
+            self.ignores.forget(key);
+            self.includes.forget(key);
+        }
+    }
+
+    /// Whether a child of a directory should be in the tree, according to both the ignore files
+    /// and the include globs.
+    fn should_track(&self, parent: Key, name: &OsStr, is_dir: bool) -> bool {
+        self.ignores.should_open(parent, name, is_dir)
+            && self.includes.should_open(parent, name, is_dir)
+    }
+
+    /// Parse an ignore file found in a directory, so that its patterns apply to the directory's
+    /// children. Lines that can't be parsed are recorded as errors, replacing the errors from the
+    /// last time the file was parsed.
+    fn read_gitignore(&mut self, dir: Key, read: impl Read) -> Result<()> {
+        let gitignore_path = self.path_to(dir).join(".gitignore");
+        let invalid = self.ignores.parse_gitignore(read, &gitignore_path, dir)?;
+
+        self.errors.retain(|(path, error)| {
+            path != &gitignore_path || !matches!(error, Error::InIgnoreFile(..))
+        });
+        for error in invalid {
+            log::warn!("Invalid line of glob: {}", error);
+            self.errors.push((gitignore_path.clone(), error));
+        }
+        Ok(())
+    }
+
+    /// Read the contents of a file in the tree, given relative to the root or as an absolute path
+    /// beneath it. Files aren't kept open while crawling, so unless the file has been read recently
+    /// and is still in the cache, it's reopened through its parent directory.
+    pub fn read_file(&mut self, path: impl AsRef<Path>) -> Result<Vec<u8>> {
+        let path = path.as_ref();
+        let relative = path.strip_prefix(&self.root_dir).unwrap_or(path);
+        let key = self.lookup(relative).ok_or_else(|| {
+            Error::from(io::Error::from(ErrorKind::NotFound))
+                .context(Operation::Open, self.root_dir.join(relative))
+        })?;
+
+        let mut file = match self.storage.take_file(key) {
+            Some(file) => file,
+            None => self.open_file(key)?,
+        };
+        let mut contents = Vec::new();
+        file.rewind()
+            .and_then(|_| Ok(file.read_to_end(&mut contents)?))
+            .map_err(|error| error.context(Operation::Read, self.path_to(key)))?;
+        self.storage.cache_file(key, file);
+
+        Ok(contents)
+    }
+
+    /// Open a file in the tree for reading, through the directory that it's in. Symlinks and
+    /// special files are opened without reading them, the same way they are while crawling.
+    fn open_file(&mut self, key: Key) -> Result<File> {
+        self.reserve_fd()?;
+        let file_type = self.storage.key_to_entry(key).map(TreeEntry::file_type);
+        let parent = self
+            .structure
+            .incoming(key)
+            .find_map(|edge| match edge.weight {
+                Connection::Child(name) if edge.connects_to != key => {
+                    Some((edge.connects_to, *name))
+                }
+                _ => None,
+            });
+        match parent {
+            Some((parent, name)) => {
+                let dir = self.dir_fd(parent);
+                let name = self.names.get(name);
+                match file_type {
+                    Some(FileType::Regular) | Some(FileType::Directory) => dir.open_at(name),
+                    _ => dir.open_path_at(name),
+                }
+                .map_err(|error| error.in_dir(self.path_to(parent)))
+            }
+            None => {
+                let path = CString::new(self.root_dir.as_os_str().as_bytes())
+                    .expect("Canonicalized path contains nul byte");
+                File::open(&path)
+            }
+        }
+    }
+
+    /// Make room to open another file within the budget, if there is one, by closing cached files
+    /// if need be.
+    fn reserve_fd(&mut self) -> Result<()> {
+        if let Some(budget) = self.max_open_files {
+            while self.storage.open_files() >= budget {
+                if !self.storage.release_file() {
+                    return Err(Error::OverBudget(budget));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The open fd of a directory in the tree.
+    fn dir_fd(&self, key: Key) -> &File {
+        self.storage
+            .key_to_entry(key)
+            .and_then(TreeEntry::fd)
+            .expect("Directory in the tree without an open fd")
+    }
+
+    /// Estimate how much memory the tree is using.
+    pub fn memory_stats(&self) -> MemoryStats {
+        let index_entry = mem::size_of::<(Key, HashMap<Name, Key>)>() + 1;
+        let child_entry = mem::size_of::<(Name, Key)>() + 1;
+        let children = self.children.capacity() * index_entry
+            + self
+                .children
+                .values()
+                .map(|children| children.capacity() * child_entry)
+                .sum::<usize>();
+
+        MemoryStats {
+            entries: self.storage.len(),
+            storage: self.storage.memory(),
+            structure: self.structure.memory() + children,
+            names: self.names.memory(),
+        }
+    }
+
+    /// The paths that couldn't be added to the tree while crawling, along with the error that
+    /// stopped each of them from being added. Everything beneath them is missing from the tree too.
+    /// Directories whose contents couldn't be read are kept in the tree, but left empty. Lines of
+    /// ignore files that couldn't be parsed are recorded here too, against the ignore file, as
+    /// `Error::InIgnoreFile`.
+    pub fn errors(&self) -> &[(PathBuf, Error)] {
+        &self.errors
+    }
+
+    /// Explain why a path, given either relative to the root or as an absolute path beneath it, is
+    /// or is not included in the tree. A path is left out if any of its parent directories are.
+    pub fn explain_ignore(&self, path: impl AsRef<Path>) -> Explanation {
+        let path = path.as_ref();
+        let relative = path.strip_prefix(&self.root_dir).unwrap_or(path);
+        let is_dir = self
+            .root_dir
+            .join(relative)
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.is_dir());
+
+        let mut names = Vec::new();
+        for component in relative.components() {
+            match component {
+                Component::Normal(name) => names.push(name),
+                Component::CurDir => continue,
+                // paths outside of the root aren't covered by any of the ignore files
+                _ => return Explanation::Included,
+            }
+        }
+
+        let mut dirs = vec![self.root_entry];
+        for (index, name) in names.iter().enumerate() {
+            let key = dirs[dirs.len() - 1];
+            let is_last = index + 1 == names.len();
+            let child = match self.child(key, name) {
+                Some(child) if !is_last => child,
+                // the rest of the path isn't in the tree, so work it out from the ignore files
+                // that have been found so far
+                _ => return self.ignores.explain_path(&dirs, &names[index..], is_dir),
             };
-            let path = Path::new(OsStr::from_bytes(path.as_bytes()));
-            if let Some(target_key) = output.follow_path(parent_key, path) {
-                output
-                    .structure
-                    .add_edge(key, target_key, Connection::SymLink);
+
+            let explanation = self.ignores.explain(&dirs, name, true);
+            if explanation.is_ignored() {
+                return explanation;
             }
+            dirs.push(child);
         }
 
-        Ok(output)
+        Explanation::Included
+    }
+
+    /// Find the entry at a path relative to the root, without following any symlinks.
+    fn lookup(&self, relative: &Path) -> Option<Key> {
+        relative
+            .components()
+            .try_fold(self.root_entry, |key, component| match component {
+                Component::Normal(name) => self.child(key, name),
+                Component::CurDir => Some(key),
+                _ => None,
+            })
+    }
+
+    /// Find the entry within a directory with the given name.
+    fn child(&self, key: Key, name: &OsStr) -> Option<Key> {
+        let name = self.names.find(name.as_bytes())?;
+        self.children.get(&key)?.get(&name).copied()
+    }
+
+    /// Add an entry to a directory.
+    fn link_child(&mut self, parent: Key, name: &CStr, child: Key) {
+        let name = self.names.intern(name);
+        self.connect(parent, child, Connection::Child(name));
+        self.children.entry(parent).or_default().insert(name, child);
+    }
+
+    /// Add an edge to the structure of the tree. Both ends must still be in the store, since the
+    /// structure can't tell a stale key from a live one in an empty slot.
+    fn connect(&mut self, from: Key, to: Key, connection: Connection) {
+        for key in [from, to] {
+            assert!(
+                self.storage.key_to_entry(key).is_some(),
+                "Attempt to connect stale key {:?}",
+                key
+            );
+        }
+        self.structure.add_edge(from, to, connection);
+    }
+
+    /// Reconstruct the path to a node in the tree, by following its parent directories back up to
+    /// the root.
+    fn path_to(&self, mut key: Key) -> PathBuf {
+        let mut names = Vec::new();
+        while key != self.root_entry {
+            let parent = self
+                .structure
+                .incoming(key)
+                .find_map(|edge| match edge.weight {
+                    Connection::Child(name) if edge.connects_to != key => {
+                        Some((edge.connects_to, *name))
+                    }
+                    _ => None,
+                });
+            match parent {
+                Some((parent, name)) => {
+                    names.push(name);
+                    key = parent;
+                }
+                None => break,
+            }
+        }
+
+        let mut path = self.root_dir.clone();
+        path.extend(
+            names
+                .into_iter()
+                .rev()
+                .map(|name| OsStr::from_bytes(self.names.get(name).to_bytes())),
+        );
+        path
     }
 
     /// Takes a position in the graph, and a path along the graph, and returns the position that
     /// that path would lead to - if that path exists and is in the walked section of the tree.
-    fn follow_path(&self, mut key: usize, path: &Path) -> Option<usize> {
+    fn follow_path(&self, mut key: Key, path: &Path) -> Option<Key> {
         for segment in path.components() {
             match segment {
                 Component::CurDir => continue,
                 Component::ParentDir => {
-                    let parent = self
-                        .structure
-                        .incoming(key)
-                        .find(|edge| edge.connects_to != key);
+                    let parent = self.structure.incoming(key).find(|edge| {
+                        matches!(edge.weight, Connection::Child(_)) && edge.connects_to != key
+                    });
                     match parent {
                         Some(parent) => key = parent.connects_to,
                         None => {
@@ -116,12 +670,15 @@ impl Tree {
                     }
                 }
                 Component::Normal(component) => {
-                    let child = self.structure.outgoing(key).find(|edge| match edge.weight {
-                        Connection::SymLink => true,
-                        Connection::Child(name) => component.as_bytes() == name.as_bytes(),
+                    // a symlink that has been resolved is followed to its target
+                    let child = self.child(key, component).map(|child| {
+                        self.structure
+                            .outgoing(child)
+                            .find(|edge| matches!(edge.weight, Connection::SymLink))
+                            .map_or(child, |edge| edge.connects_to)
                     });
                     match child {
-                        Some(child) => key = child.connects_to,
+                        Some(child) => key = child,
                         None => {
                             log::warn!("Symlink component unresolved");
                             return None;
@@ -138,95 +695,175 @@ impl Tree {
         Some(key)
     }
 
-    /// Adds a file as a child of a related file. Pushes any followup work that arises in adding
-    /// this file to unresolved_files and unresolved_symlinks.
+    /// Record that a path couldn't be added to the tree, or couldn't be scanned, and carry on
+    /// without it. The error is relative to the directory that the path is in.
+    fn record_error(&mut self, dir: Key, name: &CStr, error: Error) {
+        let dir = self.path_to(dir);
+        let error = error.in_dir(&dir);
+        let path = dir.join(OsStr::from_bytes(name.to_bytes()));
+        log::warn!("Failed to add {:?} to the tree - {}", path, error);
+        self.errors.push((path, error));
+    }
+
+    /// Adds a file as a child of a directory. Pushes any directories that need to be scanned to
+    /// new_dirs, and any symlinks that need to be resolved to unresolved_symlinks. If the file
+    /// can't be added, the error is recorded and the crawl carries on without it.
     fn add_child_file(
         &mut self,
-        parent_key: usize,
-        path: CString,
-        unresolved_files: &mut Vec<UnresolvedFile>,
+        parent_key: Key,
+        path: &CStr,
+        found: Result<Found>,
+        new_dirs: &mut Vec<Key>,
         unresolved_symlinks: &mut Vec<UnresolvedSymlink>,
-    ) -> Result<()> {
-        let parent_fd = self.storage.key_to_entry(parent_key).unwrap().fd();
-        let (file_type, inode) = parent_fd.stat_at(&path)?;
-
-        if !self.ignores.should_open(
-            parent_key,
-            OsStr::from_bytes(path.as_bytes()),
-            file_type == FileType::Directory,
-        ) {
-            return Ok(());
+    ) {
+        let result = found.and_then(|found| {
+            if path.to_bytes() == b".gitignore" && found.file_type == FileType::Regular {
+                self.reload_gitignore(parent_key)?;
+            }
+            self.add_found(parent_key, path, found, new_dirs, unresolved_symlinks)
+        });
+        if let Err(error) = result {
+            self.record_error(parent_key, path, error);
         }
+    }
 
-        let real_name = if file_type == FileType::Link {
-            Some(parent_fd.get_link_name(&path)?)
-        } else {
-            None
-        };
+    /// Parse the ignore file in a directory, unless the ignore file is ignored itself.
+    fn reload_gitignore(&mut self, dir: Key) -> Result<()> {
+        if self
+            .ignores
+            .should_open(dir, OsStr::new(".gitignore"), false)
+        {
+            let name = CString::new(".gitignore").unwrap();
+            self.reserve_fd()?;
+            let mut fd = self.dir_fd(dir).open_at(&name)?;
+            self.read_gitignore(dir, &mut fd)?;
+        }
+        Ok(())
+    }
 
-        let child_key = if let Some(key) = self.storage.inode_to_key(inode) {
-            key
-        } else {
-            let mut fd = File::open_at(parent_fd, &path)?;
-            if (path.as_bytes() == b".gitignore" && file_type == FileType::Regular) {
-                self.ignores.parse_gitignore(&mut fd, parent_key)?;
+    /// Add everything that was found while scanning a directory to the tree. The directory's
+    /// ignore file is parsed first, so that it applies to the rest of the directory's entries.
+    /// Returns the directories that were added, which need to be scanned in turn.
+    fn merge_scanned(
+        &mut self,
+        dir: Key,
+        scanned: Result<Scanned>,
+        unresolved_symlinks: &mut Vec<UnresolvedSymlink>,
+    ) -> Vec<Key> {
+        let mut new_dirs = Vec::new();
+        let scanned = match scanned {
+            Ok(scanned) => scanned,
+            Err(error) => {
+                // everything beneath the directory is left out, but the directory itself stays
+                let path = self.path_to(dir);
+                let error = error.in_dir(&path);
+                log::warn!("Failed to scan {:?} - {}", path, error);
+                self.errors.push((path, error));
+                self.report_progress(dir);
+                return new_dirs;
             }
-            let entry = TreeEntry::new(fd, inode);
-            self.add_file(entry, file_type, unresolved_files)?
         };
 
-        self.ignores
-            .open_at(parent_key, OsStr::from_bytes(path.as_bytes()), child_key);
-        self.structure
-            .add_edge(parent_key, child_key, Connection::Child(path));
+        // ignore files still apply to the included files beside them, even if they aren't
+        // included themselves
+        let gitignore = scanned.entries.iter().find(|(name, found)| {
+            name.as_bytes() == b".gitignore"
+                && matches!(found, Ok(found) if found.file_type == FileType::Regular)
+        });
+        if let Some((name, _)) = gitignore {
+            if let Err(error) = self.reload_gitignore(dir) {
+                self.record_error(dir, name, error);
+            }
+        }
 
-        if let Some(real_name) = real_name {
-            unresolved_symlinks.push(UnresolvedSymlink {
-                key: parent_key,
-                path: real_name,
-            })
+        for (name, found) in scanned.entries {
+            let result = found.and_then(|found| {
+                self.add_found(dir, &name, found, &mut new_dirs, unresolved_symlinks)
+            });
+            if let Err(error) = result {
+                self.record_error(dir, &name, error);
+            }
         }
 
-        Ok(())
+        self.report_progress(dir);
+        new_dirs
+    }
+
+    /// Count a directory as scanned, and pass the progress on to the callback if there is one.
+    fn report_progress(&mut self, dir: Key) {
+        self.progress.dirs_scanned += 1;
+        if self.on_progress.is_some() {
+            self.progress.current = self.path_to(dir);
+        }
+        if let Some(callback) = &self.on_progress {
+            callback.call(&self.progress);
+        }
     }
 
-    /// Adds a file, and then returns the ID of that file. Pushes any followup work that arises in
-    /// adding this file to unresolved_files.
-    fn add_file(
+    /// Add an entry to a directory, if the ignore files and include globs allow it.
+    fn add_found(
         &mut self,
-        entry: TreeEntry,
-        file_type: FileType,
-        unresolved_files: &mut Vec<UnresolvedFile>,
-    ) -> Result<usize> {
-        let mut children = if file_type == FileType::Directory {
-            entry.fd().scan()?
+        parent_key: Key,
+        path: &CStr,
+        found: Found,
+        new_dirs: &mut Vec<Key>,
+        unresolved_symlinks: &mut Vec<UnresolvedSymlink>,
+    ) -> Result<()> {
+        let name = OsStr::from_bytes(path.to_bytes());
+        let is_dir = found.file_type == FileType::Directory;
+        self.progress.files_seen += 1;
+        if !self.should_track(parent_key, name, is_dir) {
+            self.progress.ignored += 1;
+            return Ok(());
+        }
+
+        // symlinks are only resolved once they're known to be in the tree
+        let link = match found.file_type {
+            FileType::Link => Some(self.dir_fd(parent_key).get_link_name(path)?),
+            _ => None,
+        };
+
+        let child_key = if let Some(key) = self.storage.id_to_key(found.id) {
+            key
         } else {
-            Vec::new()
+            // only directories are kept open, everything else is reopened when it's needed
+            let fd = if is_dir {
+                self.reserve_fd()?;
+                Some(self.dir_fd(parent_key).open_at(path)?)
+            } else {
+                None
+            };
+            let entry = TreeEntry::new(fd, found.id, found.file_type);
+            let key = self
+                .storage
+                .insert(entry)
+                .map_err(|error| error.in_dir(name))?;
+            if is_dir {
+                new_dirs.push(key);
+            }
+            key
         };
-        let key = self.storage.insert(entry);
 
-        children.sort_by_key(|name| name.as_bytes() == b".gitignore");
+        if is_dir {
+            self.ignores.open_at(parent_key, name, child_key);
+            self.includes.open_at(parent_key, name, child_key);
+        }
+        self.link_child(parent_key, path, child_key);
 
-        for child_path in children {
-            unresolved_files.push(UnresolvedFile {
-                key,
-                path: child_path,
-            });
+        if let Some(real_name) = link {
+            unresolved_symlinks.push(UnresolvedSymlink {
+                key: child_key,
+                path: real_name,
+            })
         }
 
-        Ok(key)
+        Ok(())
     }
 }
 
-/// For use during construction
-struct UnresolvedFile {
-    key: usize,
-    path: CString,
-}
-
 /// For use during construction.
 struct UnresolvedSymlink {
-    key: usize,
+    key: Key,
     path: CString,
 }
 
@@ -242,7 +879,7 @@ impl Debug for Tree {
                         writeln!(f, "[{}] Symlink {:?} -> {}", last, path, edge.connects_to)?
                     }
                     Connection::Child(name) => {
-                        path.push(OsStr::from_bytes(name.as_bytes()));
+                        path.push(OsStr::from_bytes(self.names.get(*name).to_bytes()));
                         let next = edge.connects_to;
                         if next != *last {
                             writeln!(f, "[{}] File {:?}", next, path)?;
@@ -260,3 +897,531 @@ impl Debug for Tree {
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{
+        CancelToken, Connection, Event, Explanation, Found, Progress, ProgressCallback, Tree,
+        TreeOptions,
+    };
+    use crate::{
+        error::{Error, Operation},
+        fs::{DirEntry, FileType},
+    };
+    use std::{
+        ffi::CString,
+        fs,
+        ops::Deref,
+        os::unix::{ffi::OsStrExt, net::UnixListener},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+    };
+
+    /// A directory for a test to build a tree in. It's removed when the test finishes, even if the
+    /// test fails.
+    pub(crate) struct TestDir(PathBuf);
+
+    impl TestDir {
+        /// Create a directory holding the given paths, replacing anything left in it by an earlier
+        /// run. Paths ending in a slash are created as directories, and the rest as empty files.
+        pub(crate) fn new(name: &str, paths: &[&str]) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("heimdall-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+
+            let dir = TestDir(root.canonicalize().unwrap());
+            for path in paths {
+                match path.strip_suffix('/') {
+                    Some(path) => fs::create_dir_all(dir.join(path)).unwrap(),
+                    None => dir.write(path, ""),
+                }
+            }
+            dir
+        }
+
+        /// Write a file in the directory, creating the directories leading to it.
+        pub(crate) fn write(&self, path: &str, contents: impl AsRef<[u8]>) {
+            let path = self.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    impl Deref for TestDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TestDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            // the test may have removed the directory itself
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn reload_ignore() {
+        let root = TestDir::new("reload", &["build/output.o", "src/main.rs"]);
+        root.write(".gitignore", "build\n");
+
+        let mut tree = Tree::new(&root).unwrap();
+        assert!(tree.explain_ignore("build/output.o").is_ignored());
+
+        root.write(".gitignore", "src\n");
+        let mut events = tree.reload_ignore(root.join(".gitignore")).unwrap();
+        events.sort();
+        assert_eq!(
+            vec![
+                Event::Added(root.join("build")),
+                Event::Removed(root.join("src")),
+            ],
+            events
+        );
+        assert!(tree.lookup("build/output.o".as_ref()).is_some());
+        assert!(tree.lookup("src".as_ref()).is_none());
+
+        fs::remove_file(root.join(".gitignore")).unwrap();
+        let events = tree.reload_ignore(".gitignore").unwrap();
+
+        assert!(events.contains(&Event::Removed(root.join(".gitignore"))));
+        assert!(events.contains(&Event::Added(root.join("src"))));
+        assert!(tree.lookup("src/main.rs".as_ref()).is_some());
+    }
+
+    #[test]
+    fn invalid_ignore_lines() {
+        let root = TestDir::new("invalid", &["src/main.o"]);
+        root.write("src/.gitignore", "[abc\n*.o\n");
+
+        let mut tree = Tree::new(&root).unwrap();
+        let gitignore = tree.root_dir.join("src/.gitignore");
+        let check_errors = |tree: &Tree| match tree.errors() {
+            [(path, Error::InIgnoreFile(file, 1, _))] => {
+                assert_eq!(&gitignore, path);
+                assert_eq!(&gitignore, file);
+            }
+            other => panic!("Incorrect errors: {:?}", other),
+        };
+        check_errors(&tree);
+        assert!(tree.lookup("src/main.o".as_ref()).is_none());
+
+        // refreshing the directory above doesn't read the ignore file again, so its error stays
+        tree.reload_ignore(".gitignore").unwrap();
+        check_errors(&tree);
+
+        root.write("src/.gitignore", "[abc]\n*.o\n");
+        tree.reload_ignore("src/.gitignore").unwrap();
+        assert!(tree.errors().is_empty());
+    }
+
+    #[test]
+    fn explain_ignore() {
+        let root = TestDir::new("explain", &["build/output", "src/main.rs"]);
+        root.write(".gitignore", "*.o\n!keep.o\nbuild/\n*.tmp\n*.o\n");
+        root.write("src/.gitignore", "# scratch files\n*.tmp\n");
+
+        let tree = Tree::new(&root).unwrap();
+
+        let source = |path: &Path, explanation| match explanation {
+            Explanation::Matched { source, negated } => {
+                assert_eq!(path, source.path);
+                (source.line, source.pattern, negated)
+            }
+            other => panic!("Path wasn't matched: {:?}", other),
+        };
+        let gitignore = root.join(".gitignore");
+        assert_eq!(Explanation::Included, tree.explain_ignore("src/main.rs"));
+        // a repeated pattern is explained by its last line, which is the one that decided
+        assert_eq!(
+            (5, "*.o".into(), false),
+            source(&gitignore, tree.explain_ignore("src/main.o"))
+        );
+        assert_eq!(
+            (2, "!keep.o".into(), true),
+            source(&gitignore, tree.explain_ignore(root.join("keep.o")))
+        );
+        // paths beneath an ignored directory are explained by the directory's pattern
+        assert_eq!(
+            (3, "build/".into(), false),
+            source(&gitignore, tree.explain_ignore("build/output"))
+        );
+        // and by the deepest ignore file that has it
+        assert_eq!(
+            (4, "*.tmp".into(), false),
+            source(&gitignore, tree.explain_ignore("a.tmp"))
+        );
+        assert_eq!(
+            (2, "*.tmp".into(), false),
+            source(
+                &root.join("src/.gitignore"),
+                tree.explain_ignore("src/a.tmp")
+            )
+        );
+        assert_eq!(
+            Explanation::Hidden,
+            tree.explain_ignore("src/.cache/main.rs")
+        );
+        assert_eq!(Explanation::Included, tree.explain_ignore("../outside.o"));
+    }
+
+    #[test]
+    fn hard_links() {
+        let root = TestDir::new("links", &[]);
+        for index in 0..100 {
+            root.write(&format!("file-{}", index), "");
+        }
+        fs::hard_link(root.join("file-0"), root.join("link")).unwrap();
+
+        let mut tree = Tree::new(&root).unwrap();
+        let file = tree.lookup("file-0".as_ref()).unwrap();
+        assert_eq!(Some(file), tree.lookup("link".as_ref()));
+        assert!(tree.lookup("file-99".as_ref()).is_some());
+        assert!(tree.lookup("file-100".as_ref()).is_none());
+
+        // removing one name for a file leaves the other in place
+        root.write(".gitignore", "link\n");
+        tree.reload_ignore(".gitignore").unwrap();
+
+        assert!(tree.lookup("link".as_ref()).is_none());
+        assert_eq!(Some(file), tree.lookup("file-0".as_ref()));
+    }
+
+    #[test]
+    fn memory_stats() {
+        let root = TestDir::new("memory", &["a/mod.rs", "b/mod.rs"]);
+
+        let mut tree = Tree::new(&root).unwrap();
+        let stats = tree.memory_stats();
+        assert_eq!(5, stats.entries);
+        assert!(stats.names > 0 && stats.structure > 0 && stats.storage > 0);
+        assert_eq!(stats.storage + stats.structure + stats.names, stats.total());
+
+        // names are shared between directories, and released once nothing uses them
+        root.write(".gitignore", "b\n");
+        tree.reload_ignore(".gitignore").unwrap();
+        assert!(tree.names.find(b"mod.rs").is_some());
+        assert!(tree.names.find(b"b").is_none());
+
+        root.write(".gitignore", "mod.rs\n");
+        tree.reload_ignore(".gitignore").unwrap();
+        assert!(tree.names.find(b"mod.rs").is_none());
+        assert!(tree.lookup("b".as_ref()).is_some());
+    }
+
+    #[test]
+    fn include_only() {
+        let root = TestDir::new(
+            "include",
+            &[
+                "proto/api.proto",
+                "proto/generated/api.proto",
+                "proto/README",
+                "src/main.rs",
+            ],
+        );
+        root.write(".gitignore", "generated\n");
+
+        let options = TreeOptions {
+            include: vec!["**/*.proto".into()],
+            ..TreeOptions::default()
+        };
+        let tree = Tree::with_options(&root, options).unwrap();
+        assert!(tree.lookup("proto/api.proto".as_ref()).is_some());
+        assert!(tree.lookup("proto/README".as_ref()).is_none());
+        assert!(tree.lookup("proto/generated".as_ref()).is_none());
+        assert!(tree.lookup("src".as_ref()).is_some());
+        assert!(tree.lookup("src/main.rs".as_ref()).is_none());
+        assert!(tree.lookup(".gitignore".as_ref()).is_none());
+
+        // fixed paths aren't followed into directories that they can't match
+        let options = TreeOptions {
+            include: vec!["proto/*.proto".into(), "/src".into()],
+            ..TreeOptions::default()
+        };
+        let tree = Tree::with_options(&root, options).unwrap();
+
+        assert!(tree.lookup("proto/api.proto".as_ref()).is_some());
+        assert!(tree.lookup("proto/generated".as_ref()).is_none());
+        assert!(tree.lookup("src/main.rs".as_ref()).is_some());
+    }
+
+    #[test]
+    fn open_file_budget() {
+        let root = TestDir::new("budget", &["a/file", "b/file", "c/file", "d/file"]);
+
+        let options = TreeOptions {
+            max_open_files: Some(3),
+            ..TreeOptions::default()
+        };
+        let mut tree = Tree::with_options(&root, options).unwrap();
+
+        // only directories are kept open, and the root takes one of the files
+        assert_eq!(3, tree.storage.open_files());
+        assert_eq!(2, tree.errors().len());
+        for (_, error) in tree.errors() {
+            assert!(matches!(error, Error::OverBudget(3)));
+        }
+
+        // reading an ignore file needs a file of its own
+        root.write(".gitignore", "a\n");
+        let error = tree.reload_ignore(".gitignore").unwrap_err();
+        assert!(matches!(error, Error::OverBudget(3)));
+
+        // including while crawling, on any number of threads
+        for threads in &[1, 4] {
+            let options = TreeOptions {
+                max_open_files: Some(1),
+                threads: *threads,
+                ..TreeOptions::default()
+            };
+            let tree = Tree::with_options(&root, options).unwrap();
+            let gitignore = tree
+                .errors()
+                .iter()
+                .find(|(path, _)| path.ends_with(".gitignore"));
+            assert!(matches!(gitignore, Some((_, Error::OverBudget(1)))));
+        }
+    }
+
+    #[test]
+    fn progress_and_cancel() {
+        let root = TestDir::new("progress", &["a/main.rs", "a/main.o", "b/lib.rs"]);
+        root.write(".gitignore", "*.o\n");
+
+        let reports = Arc::new(Mutex::new(Vec::<Progress>::new()));
+        // cancels the crawl once it has reported its progress cancel_after times
+        let crawl = |threads, cancel: CancelToken, cancel_after: Option<usize>| {
+            reports.lock().unwrap().clear();
+            let progress = {
+                let (reports, cancel) = (reports.clone(), cancel.clone());
+                ProgressCallback::new(move |progress| {
+                    let mut reports = reports.lock().unwrap();
+                    reports.push(progress.clone());
+                    if Some(reports.len()) == cancel_after {
+                        cancel.cancel();
+                    }
+                })
+            };
+            let options = TreeOptions {
+                threads,
+                progress: Some(progress),
+                cancel,
+                ..TreeOptions::default()
+            };
+            Tree::with_options(&root, options)
+        };
+
+        for threads in &[1, 4] {
+            let tree = crawl(*threads, CancelToken::new(), None).unwrap();
+            let last = {
+                let reports = reports.lock().unwrap();
+                assert_eq!(3, reports.len());
+                reports.last().unwrap().clone()
+            };
+            assert_eq!(3, last.dirs_scanned);
+            assert_eq!(6, last.files_seen);
+            assert_eq!(1, last.ignored);
+            assert!(last.current.starts_with(&tree.root_dir));
+
+            let cancel = CancelToken::new();
+            cancel.cancel();
+            let error = crawl(*threads, cancel, None).unwrap_err();
+            assert!(matches!(error, Error::Cancelled));
+
+            // cancelling part way through stops before any more directories are merged
+            let error = crawl(*threads, CancelToken::new(), Some(1)).unwrap_err();
+            assert!(matches!(error, Error::Cancelled));
+            assert_eq!(1, reports.lock().unwrap().len());
+
+            // cancelling after the last directory is merged doesn't fail the finished crawl
+            crawl(*threads, CancelToken::new(), Some(3)).unwrap();
+            assert_eq!(3, reports.lock().unwrap().len());
+        }
+    }
+
+    #[test]
+    fn cancel_refresh() {
+        let root = TestDir::new("cancel", &["gen/a/b/file"]);
+        std::os::unix::fs::symlink("a", root.join("gen/link")).unwrap();
+
+        for threads in &[1, 4] {
+            root.write(".gitignore", "gen\n");
+            let cancel = CancelToken::new();
+            let armed = Arc::new(Mutex::new(false));
+            let progress = {
+                let (cancel, armed) = (cancel.clone(), armed.clone());
+                ProgressCallback::new(move |_| {
+                    if *armed.lock().unwrap() {
+                        cancel.cancel();
+                    }
+                })
+            };
+            let options = TreeOptions {
+                threads: *threads,
+                progress: Some(progress),
+                cancel: cancel.clone(),
+                ..TreeOptions::default()
+            };
+            let mut tree = Tree::with_options(&root, options).unwrap();
+
+            // the refresh stops after scanning gen, but keeps what it found
+            *armed.lock().unwrap() = true;
+            root.write(".gitignore", "");
+            let events = tree.reload_ignore(".gitignore").unwrap();
+            assert!(cancel.is_cancelled());
+            assert_eq!(vec![Event::Added(tree.root_dir.join("gen"))], events);
+            assert!(tree.lookup("gen/a".as_ref()).is_some());
+            assert!(tree.lookup("gen/a/b".as_ref()).is_none());
+            // the symlink found in gen is still linked up, as it would be by a full crawl
+            let links = |tree: &Tree| {
+                let link = tree.lookup("gen/link".as_ref()).unwrap();
+                tree.structure
+                    .outgoing(link)
+                    .filter(|edge| matches!(edge.weight, Connection::SymLink))
+                    .map(|edge| edge.connects_to)
+                    .collect::<Vec<_>>()
+            };
+            let full = Tree::new(&root).unwrap();
+            assert_eq!(vec![full.lookup("gen/a".as_ref()).unwrap()], links(&full));
+            assert_eq!(vec![tree.lookup("gen/a".as_ref()).unwrap()], links(&tree));
+
+            // the next refresh isn't stopped by the earlier cancel, and finishes the crawl
+            *armed.lock().unwrap() = false;
+            tree.reload_ignore(".gitignore").unwrap();
+            assert!(!cancel.is_cancelled());
+            assert!(tree.lookup("gen/a/b/file".as_ref()).is_some());
+        }
+    }
+
+    #[test]
+    fn parallel_crawl() {
+        let root = TestDir::new("parallel", &[]);
+        let mut paths = Vec::new();
+        for outer in 0..4 {
+            for inner in 0..4 {
+                let dir = format!("dir-{}/sub-{}", outer, inner);
+                root.write(&format!("{}/kept.rs", dir), "");
+                root.write(&format!("{}/skipped.o", dir), "");
+                paths.push(format!("{}/kept.rs", dir));
+            }
+            root.write(&format!("dir-{}/.gitignore", outer), "sub-0\n");
+        }
+        root.write(".gitignore", "*.o\n");
+
+        let crawl = |threads| {
+            let options = TreeOptions {
+                threads,
+                ..TreeOptions::default()
+            };
+            Tree::with_options(&root, options).unwrap()
+        };
+        let serial = crawl(1);
+        let parallel = crawl(4);
+
+        assert_eq!(serial.storage.len(), parallel.storage.len());
+        for path in &paths {
+            // each directory's ignore file leaves out its first subdirectory
+            let ignored = path.contains("sub-0");
+            let path = Path::new(path);
+            assert_eq!(!ignored, serial.lookup(path).is_some());
+            assert_eq!(!ignored, parallel.lookup(path).is_some());
+            let skipped = path.with_file_name("skipped.o");
+            assert!(parallel.lookup(&skipped).is_none());
+        }
+    }
+
+    #[test]
+    fn read_files() {
+        let root = TestDir::new("read", &[]);
+        root.write("src/main.rs", "fn main() {}");
+        root.write("src/lib.rs", "mod tree;");
+        root.write("README", "heimdall");
+
+        let options = TreeOptions {
+            max_open_files: Some(3),
+            file_cache: 2,
+            ..TreeOptions::default()
+        };
+        let mut tree = Tree::with_options(&root, options).unwrap();
+        assert_eq!(2, tree.storage.open_files());
+
+        assert_eq!(b"fn main() {}", &tree.read_file("src/main.rs").unwrap()[..]);
+        assert_eq!(b"fn main() {}", &tree.read_file("src/main.rs").unwrap()[..]);
+        // the cache gives up its files to stay within the budget
+        assert_eq!(
+            b"mod tree;",
+            &tree.read_file(root.join("src/lib.rs")).unwrap()[..]
+        );
+        assert_eq!(b"heimdall", &tree.read_file("README").unwrap()[..]);
+        assert_eq!(3, tree.storage.open_files());
+
+        let error = tree.read_file("src/missing.rs").unwrap_err();
+        assert_eq!(Some(Operation::Open), error.operation());
+    }
+
+    #[test]
+    fn crawl_errors() {
+        let root = TestDir::new("errors", &["src/main.rs"]);
+
+        let mut tree = Tree::new(&root).unwrap();
+        assert!(tree.lookup("src/main.rs".as_ref()).is_some());
+        assert!(tree.errors().is_empty());
+
+        // as if the file was deleted between scanning its directory and adding it
+        let name = CString::new("missing").unwrap();
+        let entry = DirEntry {
+            name: name.clone(),
+            file_type: None,
+            inode: 0,
+        };
+        let found = Found::find_all(tree.dir_fd(tree.root_entry), vec![entry]).unwrap();
+        let found = found.into_iter().next().unwrap().1;
+        tree.add_child_file(
+            tree.root_entry,
+            &name,
+            found,
+            &mut Vec::new(),
+            &mut Vec::new(),
+        );
+
+        let missing = tree.root_dir.join("missing");
+        match tree.errors() {
+            [(path, error)] => {
+                assert_eq!(&missing, path);
+                assert_eq!(Some(missing.as_path()), error.path());
+                assert_eq!(Some(Operation::Stat), error.operation());
+                assert!(error.to_string().contains(&*missing.to_string_lossy()));
+            }
+            other => panic!("Incorrect errors: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn special_files() {
+        let root = TestDir::new("special", &[]);
+        let fifo = CString::new(root.join("fifo").as_os_str().as_bytes()).unwrap();
+        assert_eq!(0, unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) });
+        let _listener = UnixListener::bind(root.join("socket")).unwrap();
+
+        // opening the FIFO to read from it would wait for a writer
+        let tree = Tree::new(&root).unwrap();
+        assert!(tree.errors().is_empty());
+
+        let file_type = |name: &str| {
+            let key = tree.lookup(name.as_ref()).unwrap();
+            tree.storage.key_to_entry(key).unwrap().file_type()
+        };
+        assert_eq!(FileType::Fifo, file_type("fifo"));
+        assert_eq!(FileType::Socket, file_type("socket"));
+    }
+}
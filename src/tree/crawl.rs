@@ -0,0 +1,215 @@
+use super::Key;
+use crate::{
+    error::Result,
+    fs::{DirEntry, File, FileId, FileType},
+};
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use std::{
+    ffi::CString,
+    iter,
+    mem::ManuallyDrop,
+    os::unix::io::{FromRawFd, RawFd},
+    sync::{mpsc, Condvar, Mutex},
+    thread,
+};
+
+/// What was found out about a directory entry, before deciding whether it belongs in the tree.
+pub struct Found {
+    pub file_type: FileType,
+    pub id: FileId,
+}
+
+impl Found {
+    /// Look up the entries from a directory's listing, using what the listing says about them
+    /// where possible. Errors are given a path relative to the directory.
+    ///
+    /// Entries that aren't directories are identified by the directory's device and the inode from
+    /// the listing, without looking them up. On most local filesystems, such as ext4, xfs, btrfs
+    /// and tmpfs, that's the same as looking them up would give. It isn't for files that are bind
+    /// mounted over, or on overlayfs without xino, where a lookup would give a different device or
+    /// inode. Every entry that's added to the tree is identified this way, so that the same file is
+    /// always given the same id either way.
+    pub fn find_all(
+        dir: &File,
+        mut entries: Vec<DirEntry>,
+    ) -> Result<Vec<(CString, Result<Self>)>> {
+        // the directory itself is already known
+        entries.retain(|entry| entry.name.as_bytes() != b".");
+        let (_, FileId { dev, .. }) = dir.stat()?;
+        let unlisted = entries
+            .iter()
+            .filter(|entry| needs_stat(entry))
+            .map(|entry| &*entry.name)
+            .collect::<Vec<_>>();
+        let mut stats = dir.stat_all_at(&unlisted).into_iter();
+
+        let found = entries
+            .into_iter()
+            .map(|entry| {
+                let stat = if needs_stat(&entry) {
+                    stats.next().expect("Missing lookup for directory entry")
+                } else {
+                    let id = FileId {
+                        dev,
+                        inode: entry.inode,
+                    };
+                    Ok((entry.file_type.unwrap(), id))
+                };
+                let found = stat.map(|(file_type, id)| Found { file_type, id });
+                (entry.name, found)
+            })
+            .collect();
+        Ok(found)
+    }
+}
+
+/// Everything in a directory, gathered without touching the tree so that it can be done on any
+/// thread. Anything that depends on the ignore files, such as reading them or following symlinks,
+/// is left for the tree to do as the entries are added.
+pub struct Scanned {
+    pub entries: Vec<(CString, Result<Found>)>,
+}
+
+impl Scanned {
+    /// Scan a directory, and look up each of its entries. Errors are given a path relative to the
+    /// directory.
+    pub fn scan(dir: &File) -> Result<Self> {
+        let entries = Found::find_all(dir, dir.scan()?)?;
+        Ok(Scanned { entries })
+    }
+}
+
+/// Whether an entry has to be looked up, because the listing doesn't say enough about it.
+/// Directories are always looked up, since they may be mount points, which are listed with the
+/// inode of the directory underneath.
+fn needs_stat(entry: &DirEntry) -> bool {
+    matches!(entry.file_type, None | Some(FileType::Directory))
+}
+
+/// A directory waiting to be scanned, along with the fd that the tree holds for it.
+#[derive(Debug, Copy, Clone)]
+struct Job {
+    key: Key,
+    fd: RawFd,
+}
+
+/// The directories waiting to be scanned, shared between the crawl threads. Each thread takes
+/// batches of directories from the shared queue into its own queue, and steals from the other
+/// threads once the shared queue is empty.
+struct Queue {
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    // set once there are no more directories coming, which threads without work wait for
+    finished: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+impl Queue {
+    fn push(&self, job: Job) {
+        self.injector.push(job);
+        let _guard = self.finished.lock().unwrap();
+        self.wakeup.notify_one();
+    }
+
+    fn finish(&self) {
+        *self.finished.lock().unwrap() = true;
+        self.wakeup.notify_all();
+    }
+
+    /// Find a directory to scan, waiting for one if there aren't any. Returns None once the crawl
+    /// is finished.
+    fn pop(&self, local: &Worker<Job>) -> Option<Job> {
+        loop {
+            // the crawl may have stopped early, leaving directories in the queues
+            if *self.finished.lock().unwrap() {
+                return None;
+            }
+            if let Some(job) = self.find(local) {
+                return Some(job);
+            }
+
+            let finished = self.finished.lock().unwrap();
+            if *finished {
+                return None;
+            }
+            // a directory may have been pushed before the lock was taken, which wouldn't wake
+            // this thread
+            if self.injector.is_empty() {
+                drop(self.wakeup.wait(finished).unwrap());
+            }
+        }
+    }
+
+    fn find(&self, local: &Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+}
+
+/// Scan directories on a pool of threads, starting with the given directories and their fds.
+/// Each directory that is scanned is passed to merge on the calling thread, which returns any more
+/// directories that need to be scanned, or None to stop early. Returns once there are none left.
+/// Every fd must stay open until this returns.
+pub fn scan_parallel(
+    threads: usize,
+    start: Vec<(Key, RawFd)>,
+    mut merge: impl FnMut(Key, Result<Scanned>) -> Option<Vec<(Key, RawFd)>>,
+) {
+    let workers = iter::repeat_with(Worker::new_fifo)
+        .take(threads)
+        .collect::<Vec<_>>();
+    let queue = Queue {
+        injector: Injector::new(),
+        stealers: workers.iter().map(Worker::stealer).collect(),
+        finished: Mutex::new(false),
+        wakeup: Condvar::new(),
+    };
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for local in workers {
+            let sender = sender.clone();
+            let queue = &queue;
+            scope.spawn(move || {
+                while let Some(Job { key, fd }) = queue.pop(&local) {
+                    // the fd is still owned by the tree, so it mustn't be closed here
+                    let dir = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+                    if sender.send((key, Scanned::scan(&dir))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+        // let the threads stop even if merging panics
+        scopeguard::defer! {
+            queue.finish();
+        }
+
+        let mut outstanding = 0;
+        let mut pending = start;
+        loop {
+            outstanding += pending.len();
+            for (key, fd) in pending {
+                queue.push(Job { key, fd });
+            }
+            if outstanding == 0 {
+                break;
+            }
+
+            let (key, scanned) = receiver.recv().expect("Crawl threads stopped early");
+            outstanding -= 1;
+            pending = match merge(key, scanned) {
+                Some(pending) => pending,
+                None => break,
+            };
+        }
+    });
+}
@@ -1,89 +1,539 @@
+use super::Key;
 use crate::{
-    error::Result,
-    fs::File,
-    glob::{GlobArena, GlobKey},
+    error::{Error, Operation, Result},
+    glob::{GlobArena, GlobKey, GlobSet},
 };
 use std::{
     collections::HashMap,
     ffi::OsStr,
-    io::{BufRead, BufReader},
+    fs,
+    io::{BufRead, BufReader, Read},
     os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    rc::{Rc, Weak},
 };
 
+/// Strip the trailing spaces from a line of a gitignore file, like git does, keeping a space that
+/// has been escaped with a backslash. Any other whitespace is part of the pattern, apart from the
+/// carriage return of a CRLF line ending.
+fn trim_line(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let mut len = line.len();
+    while len > 0 && line[len - 1] == b' ' {
+        let backslashes = line[..len - 1]
+            .iter()
+            .rev()
+            .take_while(|&&letter| letter == b'\\')
+            .count();
+        if backslashes % 2 == 1 {
+            break;
+        }
+        len -= 1;
+    }
+
+    &line[..len]
+}
+
+/// The line of an ignore file that a glob was parsed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IgnoreSource {
+    /// The path to the ignore file.
+    pub path: PathBuf,
+    /// The line number within the ignore file, starting from 1.
+    pub line: usize,
+    /// The pattern, as it was written in the ignore file.
+    pub pattern: String,
+}
+
+/// The reason that a path is, or is not, included in a tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Explanation {
+    /// No ignore rules mention the path, so it is included.
+    Included,
+    /// The path, or one of its parent directories, is a hidden file, so it is left out.
+    Hidden,
+    /// A line of an ignore file matched the path or one of its parent directories. The path is
+    /// left out, unless the line was negated with a leading `!`.
+    Matched { source: IgnoreSource, negated: bool },
+}
+
+impl Explanation {
+    /// Whether the path is left out of the tree.
+    pub fn is_ignored(&self) -> bool {
+        match self {
+            Explanation::Included => false,
+            Explanation::Hidden => true,
+            Explanation::Matched { negated, .. } => !negated,
+        }
+    }
+}
+
+/// The lines of a gitignore file that were compiled, and the errors for the ones that weren't.
+struct ParsedLines {
+    globs: Vec<(GlobKey, IgnoreSource)>,
+    invalid: Vec<Error>,
+}
+
+/// Compile each line of a gitignore file into the arena, skipping comments and blank lines. Lines
+/// that fail to compile are skipped, and returned as errors along with the file they came from.
+fn parse_lines(
+    arena: &mut GlobArena,
+    read: impl Read,
+    source: &Path,
+    ignore_case: bool,
+) -> Result<ParsedLines> {
+    let mut globs = Vec::new();
+    let mut invalid = Vec::new();
+
+    let read = BufReader::new(read);
+    for (index, line) in read.split(b'\n').enumerate() {
+        let line = line.map_err(|error| Error::from(error).context(Operation::Read, source))?;
+        let line = trim_line(&line);
+        if line.starts_with(b"#") || line.is_empty() {
+            continue;
+        }
+        match arena.compile_glob(OsStr::from_bytes(line), ignore_case) {
+            Ok(key) => globs.push((
+                key,
+                IgnoreSource {
+                    path: source.to_path_buf(),
+                    line: index + 1,
+                    pattern: String::from_utf8_lossy(line).into_owned(),
+                },
+            )),
+            Err(err) => {
+                invalid.push(Error::InIgnoreFile(
+                    source.to_path_buf(),
+                    index + 1,
+                    Box::new(err),
+                ));
+            }
+        }
+    }
+
+    Ok(ParsedLines { globs, invalid })
+}
+
+/// Hidden files are never opened, apart from the ignore files themselves.
+fn is_hidden(name: &OsStr) -> bool {
+    let name = name.as_bytes();
+    name.starts_with(b".") && name != b".gitignore"
+}
+
+/// Find the glob that decides whether a file should be opened, from the results of matching it
+/// against each of the globs that apply to it (true for a glob that ignores the file, false for a
+/// negated glob). Negated globs take priority. Returns None if no glob matched.
+pub(super) fn decide(results: impl Iterator<Item = (GlobKey, bool)>) -> Option<(GlobKey, bool)> {
+    results.fold(None, |old, new| match (old, new) {
+        (Some((_, false)), _) => old,
+        (_, (_, false)) => Some(new),
+        (old, new) => old.or(Some(new)),
+    })
+}
+
+/// Decide whether a file should be opened, from the results of matching it against each of the
+/// globs that apply to it.
+fn should_open(results: impl Iterator<Item = (GlobKey, bool)>) -> bool {
+    // if the gitignore doesn't mention the file, open it
+    decide(results).is_none_or(|(_, ignored)| !ignored)
+}
+
 pub struct Ignore {
     arena: GlobArena,
-    key_to_globs: HashMap<usize, Vec<GlobKey>>,
+    // the globs that apply to the children of each directory: those inherited from its parent,
+    // followed by those from its own ignore file
+    key_to_globs: HashMap<Key, Rc<GlobSet>>,
+    // the globs from each directory's own ignore file, and the lines they came from
+    own_globs: HashMap<Key, Vec<(GlobKey, IgnoreSource)>>,
+    // compiled glob sets, shared between every directory that has the same globs applied to it.
+    // Sets that no directory uses any more are dropped, and their entries collected in intern.
+    set_cache: HashMap<Vec<GlobKey>, Weak<GlobSet>>,
+    // the size of the set cache at which to next collect its dead entries
+    next_collection: usize,
+    ignore_case: bool,
 }
 
+/// The smallest size of the set cache at which its dead entries are collected.
+const MIN_COLLECTION: usize = 64;
+
 impl Ignore {
-    pub fn new() -> Self {
+    /// Create a new set of ignore rules. If ignore_case is set, the patterns in gitignore files
+    /// will match names regardless of their case (like git's core.ignorecase).
+    pub fn new(ignore_case: bool) -> Self {
         Ignore {
             arena: GlobArena::new(),
             key_to_globs: HashMap::new(),
+            own_globs: HashMap::new(),
+            set_cache: HashMap::new(),
+            next_collection: MIN_COLLECTION,
+            ignore_case,
         }
     }
 
-    pub fn parse_gitignore(&mut self, fd: &mut File, at: usize) -> Result<()> {
-        let mut new_globs = Vec::new();
+    /// Find the compiled set for a list of globs, compiling it if no other directory uses it yet.
+    fn intern(&mut self, keys: Vec<GlobKey>) -> Rc<GlobSet> {
+        if let Some(set) = self.set_cache.get(&keys).and_then(Weak::upgrade) {
+            return set;
+        }
 
-        let read = BufReader::new(fd);
-        for line in read.lines() {
-            let line = line?;
-            if line.starts_with('#') || line.trim_end().is_empty() {
-                continue;
-            }
-            match self.arena.compile_glob(line.as_str().trim_end()) {
-                Ok(key) => new_globs.push(key),
-                Err(err) => log::warn!("Invalid line of glob: {}", err),
-            }
+        if self.set_cache.len() >= self.next_collection {
+            self.set_cache.retain(|_, set| set.strong_count() > 0);
+            self.next_collection = MIN_COLLECTION.max(self.set_cache.len() * 2);
         }
 
-        self.key_to_globs
-            .entry(at)
-            .or_insert_with(Vec::new)
-            .extend_from_slice(new_globs.as_ref());
+        let set = Rc::new(self.arena.compile_set(keys.clone()));
+        self.set_cache.insert(keys, Rc::downgrade(&set));
+        set
+    }
 
-        Ok(())
+    /// The globs that a directory inherits from its parent.
+    fn inherited(&self, at: Key) -> Vec<GlobKey> {
+        let own = self.own_globs.get(&at).map_or(0, Vec::len);
+        self.key_to_globs.get(&at).map_or_else(Vec::new, |globs| {
+            let keys = globs.keys();
+            keys[..keys.len() - own].to_vec()
+        })
     }
 
-    pub fn should_open(&self, parent: usize, name: &OsStr, is_dir: bool) -> bool {
-        {
-            let name = name.as_bytes();
-            if name.starts_with(b".") && name != b".gitignore" {
-                return false;
-            }
+    /// Set the globs that apply to the children of a directory, from those it inherits and those
+    /// in its own ignore file.
+    fn set_globs(&mut self, at: Key, mut keys: Vec<GlobKey>) {
+        keys.extend(
+            self.own_globs
+                .get(&at)
+                .into_iter()
+                .flatten()
+                .map(|&(key, _)| key),
+        );
+
+        if keys.is_empty() {
+            self.key_to_globs.remove(&at);
+        } else {
+            let set = self.intern(keys);
+            self.key_to_globs.insert(at, set);
         }
+    }
+
+    /// Remove the globs from a directory's own ignore file, from the arena and from any cached
+    /// sets that use them. The globs that apply to the directory must be set again afterwards.
+    fn remove_own_globs(&mut self, at: Key) {
+        let old_globs = match self.own_globs.remove(&at) {
+            Some(old_globs) => old_globs,
+            None => return,
+        };
+
+        let mut freed = false;
+        for (key, _) in old_globs {
+            freed |= self.arena.remove(key);
+        }
+        // the keys of freed globs may be reused, so sets containing them must not be found again
+        if freed {
+            let arena = &self.arena;
+            self.set_cache
+                .retain(|keys, _| keys.iter().all(|&key| arena.contains(key)));
+        }
+    }
+
+    /// Parse a gitignore file, found at the given path, whose globs apply to the children of the
+    /// directory at. This replaces any globs from an ignore file previously parsed for the same
+    /// directory, but the directories beneath it need to be opened again with open_at to see the
+    /// change. Returns an error for each line that couldn't be parsed, which is left out.
+    pub fn parse_gitignore(&mut self, read: impl Read, path: &Path, at: Key) -> Result<Vec<Error>> {
+        let ParsedLines {
+            globs: new_globs,
+            invalid,
+        } = parse_lines(&mut self.arena, read, path, self.ignore_case)?;
+
+        let inherited = self.inherited(at);
+        self.remove_own_globs(at);
+        if !new_globs.is_empty() {
+            self.own_globs.insert(at, new_globs);
+        }
+        self.set_globs(at, inherited);
+
+        Ok(invalid)
+    }
+
+    /// Forget everything about a directory that has been removed from the tree.
+    pub fn forget(&mut self, at: Key) {
+        self.remove_own_globs(at);
+        self.key_to_globs.remove(&at);
+    }
+
+    fn match_file<'a>(
+        &'a self,
+        parent: Key,
+        name: &'a OsStr,
+        is_dir: bool,
+    ) -> impl Iterator<Item = (GlobKey, bool)> + 'a {
         self.key_to_globs
             .get(&parent)
             .into_iter()
-            .flat_map(|globs| globs.iter())
-            .cloned()
-            // test the name against the globs
-            .map(|glob| self.arena.match_file(glob, name, is_dir))
-            // turn from "does the file match" to "should we open the file"
-            .map(|opt| opt.map(|x| !x))
-            .fold(None, |old, new| match (old, new) {
-                (Some(true), _) | (_, Some(true)) => Some(true),
-                (old, new) => old.or(new),
-            })
-            // if the gitignore doesn't mention the file, open it
-            .unwrap_or(true)
-    }
-
-    pub fn open_at(&mut self, parent: usize, name: &OsStr, child: usize) {
-        let new_globs = self
+            .flat_map(move |globs| self.arena.match_file_set(globs, name, is_dir))
+    }
+
+    pub fn should_open(&self, parent: Key, name: &OsStr, is_dir: bool) -> bool {
+        !is_hidden(name) && should_open(self.match_file(parent, name, is_dir))
+    }
+
+    /// Turn the glob that decided whether to open a file into an explanation. Identical patterns
+    /// share a key, so like git, the line it came from is taken to be the last one in the deepest
+    /// ignore file that has it. The directories that apply are given from the root down.
+    fn explanation(&self, dirs: &[Key], decision: Option<(GlobKey, bool)>) -> Explanation {
+        let (key, ignored) = match decision {
+            Some(decision) => decision,
+            None => return Explanation::Included,
+        };
+
+        let key = self.arena.pattern_key(key);
+        let source = dirs
+            .iter()
+            .rev()
+            .flat_map(|dir| self.own_globs.get(dir))
+            .flat_map(|globs| globs.iter().rev())
+            .find(|&&(own, _)| own == key)
+            .map(|(_, source)| source.clone())
+            .expect("Matched a glob from outside the directories that apply");
+
+        Explanation::Matched {
+            source,
+            negated: !ignored,
+        }
+    }
+
+    /// Explain why a child of a directory is, or is not, opened. The directories are given from
+    /// the root down to the parent of the child.
+    pub fn explain(&self, dirs: &[Key], name: &OsStr, is_dir: bool) -> Explanation {
+        if is_hidden(name) {
+            return Explanation::Hidden;
+        }
+
+        let parent = *dirs.last().expect("No parent directory given");
+        self.explanation(dirs, decide(self.match_file(parent, name, is_dir)))
+    }
+
+    /// Explain why a path beneath a directory would be opened or not, when the directories along
+    /// that path have not been opened (and so have no ignore files of their own). The directories
+    /// are given from the root down to the one that the path starts from.
+    pub fn explain_path(&self, dirs: &[Key], names: &[&OsStr], is_dir: bool) -> Explanation {
+        let parent = *dirs.last().expect("No parent directory given");
+        let mut globs = self
             .key_to_globs
             .get(&parent)
-            .into_iter()
-            .flat_map(|globs| globs.iter())
-            .cloned()
-            .filter_map(|glob| self.arena.match_dir(glob, name))
-            .flatten()
-            .collect::<Vec<_>>();
+            .map_or_else(Vec::new, |globs| globs.keys().to_vec());
+        let mut explanation = Explanation::Included;
 
-        self.key_to_globs
-            .entry(child)
-            .or_insert_with(Vec::new)
-            .extend_from_slice(new_globs.as_ref())
+        for (index, name) in names.iter().enumerate() {
+            if is_hidden(name) {
+                return Explanation::Hidden;
+            }
+
+            let is_last = index + 1 == names.len();
+            let results = globs.iter().filter_map(|&glob| {
+                self.arena
+                    .match_file(glob, name, is_dir || !is_last)
+                    .map(|result| (glob, result))
+            });
+            explanation = self.explanation(dirs, decide(results));
+            if explanation.is_ignored() {
+                break;
+            }
+
+            globs = globs
+                .iter()
+                .filter_map(|&glob| self.arena.match_dir(glob, name))
+                .flatten()
+                .collect();
+        }
+
+        explanation
+    }
+
+    /// Work out which globs apply to the children of a directory, from the globs that apply to
+    /// its parent. This is done when the directory is first opened, and again whenever the globs
+    /// that apply to its parent change.
+    pub fn open_at(&mut self, parent: Key, name: &OsStr, child: Key) {
+        let inherited = match self.key_to_globs.get(&parent) {
+            Some(globs) => self.arena.match_dir_set(globs, name),
+            None => Vec::new(),
+        };
+
+        self.set_globs(child, inherited);
+    }
+}
+
+/// Decides whether paths are ignored by a collection of gitignore files, using the same rules
+/// that a tree uses while it is crawling. This includes skipping hidden files.
+pub struct Gitignore {
+    root: PathBuf,
+    arena: GlobArena,
+    // the globs from the ignore file in each directory, keyed by the path relative to the root
+    dir_to_globs: HashMap<PathBuf, Vec<GlobKey>>,
+    ignore_case: bool,
+}
+
+impl Gitignore {
+    /// Create a matcher, with no ignore files, for paths beneath the root directory. If
+    /// ignore_case is set, patterns will match names regardless of their case.
+    pub fn new(root: impl AsRef<Path>, ignore_case: bool) -> Self {
+        Gitignore {
+            root: root.as_ref().to_path_buf(),
+            arena: GlobArena::new(),
+            dir_to_globs: HashMap::new(),
+            ignore_case,
+        }
+    }
+
+    /// Create a matcher from a list of gitignore files. The patterns in each file apply to the
+    /// directory that the file is in.
+    pub fn from_files(
+        root: impl AsRef<Path>,
+        files: impl IntoIterator<Item = impl AsRef<Path>>,
+        ignore_case: bool,
+    ) -> Result<Self> {
+        let mut output = Self::new(root, ignore_case);
+        for file in files {
+            output.add_file(file)?;
+        }
+
+        Ok(output)
+    }
+
+    /// Strip the root from a path, if it is absolute.
+    fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.root).unwrap_or(path)
+    }
+
+    /// Add a gitignore file, given either relative to the root or as an absolute path beneath it.
+    pub fn add_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = self.root.join(self.relative(path.as_ref()));
+        let dir = self
+            .relative(path.parent().unwrap_or(&self.root))
+            .to_path_buf();
+
+        let file = fs::File::open(&path)
+            .map_err(|error| Error::from(error).context(Operation::Open, &path))?;
+        let ParsedLines {
+            globs: new_globs,
+            invalid,
+        } = parse_lines(&mut self.arena, file, &path, self.ignore_case)?;
+        for error in invalid {
+            log::warn!("Invalid line of glob: {}", error);
+        }
+        self.dir_to_globs
+            .entry(dir)
+            .or_default()
+            .extend(new_globs.into_iter().map(|(key, _)| key));
+
+        Ok(())
+    }
+
+    /// Test whether a path, either relative to the root or as an absolute path beneath it, would
+    /// be left out of a tree at the root. A path is also ignored if any of its parent directories
+    /// are ignored.
+    pub fn is_ignored(&self, path: impl AsRef<Path>, is_dir: bool) -> bool {
+        let mut names = Vec::new();
+        for component in self.relative(path.as_ref()).components() {
+            match component {
+                Component::Normal(name) => names.push(name),
+                Component::CurDir => continue,
+                // paths outside of the root aren't covered by any of the ignore files
+                _ => return false,
+            }
+        }
+
+        let mut dir = PathBuf::new();
+        let mut globs = self.dir_to_globs.get(&dir).cloned().unwrap_or_default();
+
+        for (index, name) in names.iter().enumerate() {
+            let is_last = index + 1 == names.len();
+            let results = globs.iter().filter_map(|&glob| {
+                self.arena
+                    .match_file(glob, name, is_dir || !is_last)
+                    .map(|result| (glob, result))
+            });
+            if is_hidden(name) || !should_open(results) {
+                return true;
+            }
+
+            dir.push(name);
+            globs = globs
+                .iter()
+                .filter_map(|&glob| self.arena.match_dir(glob, name))
+                .flatten()
+                .chain(self.dir_to_globs.get(&dir).into_iter().flatten().cloned())
+                .collect();
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decide, trim_line, Gitignore};
+    use crate::{error::Operation, glob::GlobArena, tree::test::TestDir};
+
+    #[test]
+    fn nested_gitignores() {
+        let root = TestDir::new("gitignore", &[]);
+        root.write(".gitignore", "*.o\n!keep.o\ntarget\n");
+        root.write("sub/.gitignore", "generated\n");
+
+        let ignore = Gitignore::from_files(&root, [".gitignore", "sub/.gitignore"], false).unwrap();
+
+        assert!(ignore.is_ignored("main.o", false));
+        assert!(ignore.is_ignored("sub/main.o", false));
+        assert!(!ignore.is_ignored("keep.o", false));
+        assert!(!ignore.is_ignored("main.c", false));
+        assert!(ignore.is_ignored("target", true));
+        assert!(ignore.is_ignored("target/debug/hd", false));
+        assert!(ignore.is_ignored("sub/generated", false));
+        assert!(!ignore.is_ignored("generated", false));
+        assert!(ignore.is_ignored(root.join("sub/generated"), false));
+        assert!(ignore.is_ignored(".hidden", false));
+
+        let error = match Gitignore::from_files(&root, ["missing/.gitignore"], false) {
+            Ok(_) => panic!("Missing ignore file was read"),
+            Err(error) => error,
+        };
+        assert_eq!(Some(Operation::Open), error.operation());
+        assert_eq!(
+            Some(root.join("missing/.gitignore").as_path()),
+            error.path()
+        );
+    }
+
+    #[test]
+    fn trimmed_lines() {
+        assert_eq!(b"target", trim_line(b"target   "));
+        assert_eq!(b"target", trim_line(b"target \r"));
+        assert_eq!(b"target\\ ", trim_line(b"target\\ "));
+        assert_eq!(b"target\\ ", trim_line(b"target\\   "));
+        assert_eq!(b"target\\\\", trim_line(b"target\\\\ "));
+        assert_eq!(b"\ttarget", trim_line(b"\ttarget"));
+        assert_eq!(b"  target\t", trim_line(b"  target\t"));
+        assert_eq!(b"", trim_line(b"   "));
+    }
+
+    #[test]
+    fn negated_globs_decide() {
+        let mut arena = GlobArena::new();
+        let ignore = arena.compile_glob("*.o", false).unwrap();
+        let negated = arena.compile_glob("!keep.o", false).unwrap();
+
+        assert_eq!(None, decide(vec![].into_iter()));
+        assert_eq!(
+            Some((ignore, true)),
+            decide(vec![(ignore, true)].into_iter())
+        );
+        assert_eq!(
+            Some((negated, false)),
+            decide(vec![(ignore, true), (negated, false)].into_iter())
+        );
+        assert_eq!(
+            Some((negated, false)),
+            decide(vec![(negated, false), (ignore, true)].into_iter())
+        );
     }
 }
@@ -0,0 +1,109 @@
+use super::{ignore::decide, Key};
+use crate::{
+    error::Result,
+    glob::{GlobArena, GlobKey},
+};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+};
+
+/// Restricts a tree to the paths that match a list of include globs. A directory is only crawled
+/// if one of the globs could still match something beneath it, and a directory that matches a
+/// glob has everything beneath it included.
+pub struct Include {
+    arena: GlobArena,
+    // the globs that apply to the root directory
+    root_globs: Vec<GlobKey>,
+    // the globs that could still match the children of each directory. Every child of a
+    // directory that isn't in here is included.
+    key_to_globs: HashMap<Key, Vec<GlobKey>>,
+}
+
+impl Include {
+    /// Compile a list of include globs, which use the same syntax as lines of a gitignore file and
+    /// are relative to the root of the tree. If the list is empty, everything is included.
+    pub fn new(globs: &[OsString], ignore_case: bool) -> Result<Self> {
+        let mut arena = GlobArena::new();
+        let root_globs = globs
+            .iter()
+            .map(|glob| arena.compile_glob(glob, ignore_case))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Include {
+            arena,
+            root_globs,
+            key_to_globs: HashMap::new(),
+        })
+    }
+
+    /// Apply the include globs to the children of the root directory.
+    pub fn open_root(&mut self, root: Key) {
+        if !self.root_globs.is_empty() {
+            self.key_to_globs.insert(root, self.root_globs.clone());
+        }
+    }
+
+    /// Find the include glob that decides whether a child of the directory parent is included, as
+    /// well as the globs that apply to the directory parent. Returns None if every child is
+    /// included.
+    fn decide(
+        &self,
+        parent: Key,
+        name: &OsStr,
+        is_dir: bool,
+    ) -> Option<(&[GlobKey], Option<bool>)> {
+        let globs = self.key_to_globs.get(&parent)?;
+        let results = globs.iter().filter_map(|&glob| {
+            self.arena
+                .match_file(glob, name, is_dir)
+                .map(|result| (glob, result))
+        });
+
+        Some((globs, decide(results).map(|(_, included)| included)))
+    }
+
+    /// Whether a child of the directory parent should be added to the tree. Directories that
+    /// don't match a glob themselves are still added if a glob could match one of their children.
+    pub fn should_open(&self, parent: Key, name: &OsStr, is_dir: bool) -> bool {
+        match self.decide(parent, name, is_dir) {
+            None => true,
+            Some((_, Some(included))) => included,
+            Some((globs, None)) => {
+                is_dir
+                    && globs
+                        .iter()
+                        .filter(|&&glob| !self.arena.is_negated(glob))
+                        .any(|&glob| self.arena.match_dir(glob, name).is_some())
+            }
+        }
+    }
+
+    /// Work out which globs apply to the children of a directory, from the globs that apply to
+    /// its parent.
+    pub fn open_at(&mut self, parent: Key, name: &OsStr, child: Key) {
+        let globs = match self.decide(parent, name, true) {
+            Some((globs, None)) => {
+                let mut globs = globs
+                    .iter()
+                    .filter_map(|&glob| self.arena.match_dir(glob, name))
+                    .flatten()
+                    .collect::<Vec<_>>();
+                globs.sort_unstable();
+                globs.dedup();
+                globs
+            }
+            _ => {
+                self.key_to_globs.remove(&child);
+                return;
+            }
+        };
+
+        self.key_to_globs.insert(child, globs);
+    }
+
+    /// Forget everything about a directory that has been removed from the tree.
+    pub fn forget(&mut self, at: Key) {
+        self.key_to_globs.remove(&at);
+    }
+}
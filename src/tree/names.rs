@@ -0,0 +1,182 @@
+use std::{convert::TryFrom, ffi::CStr, hash::BuildHasher, mem};
+
+use ahash::RandomState;
+use hashbrown::raw::RawTable;
+use slab::Slab;
+
+/// A name stored in a name arena.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Name(u32);
+
+/// Where a name is in the arena's buffer, and how many times it has been interned.
+#[derive(Debug)]
+struct Span {
+    offset: u32,
+    // includes the nul byte
+    length: u32,
+    references: u32,
+}
+
+/// Interned storage for the names of directory entries. Every name is kept once, nul terminated,
+/// in a single shared buffer, and names are reference counted so that the space used by names that
+/// are no longer needed can be reclaimed.
+pub struct NameArena {
+    bytes: Vec<u8>,
+    spans: Slab<Span>,
+    index: (RawTable<u32>, RandomState),
+    // the number of bytes in the buffer that belong to released names
+    garbage: usize,
+}
+
+/// The buffer isn't compacted until there's at least this much garbage in it.
+const MIN_GARBAGE: usize = 4096;
+
+impl NameArena {
+    /// Create a new, empty name arena.
+    pub fn new() -> Self {
+        NameArena {
+            bytes: Vec::new(),
+            spans: Slab::new(),
+            index: (RawTable::new(), RandomState::new()),
+            garbage: 0,
+        }
+    }
+
+    fn span_bytes<'a>(bytes: &'a [u8], span: &Span) -> &'a [u8] {
+        let start = span.offset as usize;
+        &bytes[start..start + span.length as usize - 1]
+    }
+
+    /// Find a name that has already been interned, without interning it.
+    pub fn find(&self, name: &[u8]) -> Option<Name> {
+        let (table, hasher) = &self.index;
+        table
+            .find(hasher.hash_one(name), |&slot| {
+                Self::span_bytes(&self.bytes, &self.spans[slot as usize]) == name
+            })
+            .map(|bucket| Name(unsafe { *bucket.as_ref() }))
+    }
+
+    /// Intern a name, returning a handle to it. The name must be released once for every time it
+    /// has been interned.
+    pub fn intern(&mut self, name: &CStr) -> Name {
+        if let Some(existing) = self.find(name.to_bytes()) {
+            self.spans[existing.0 as usize].references += 1;
+            return existing;
+        }
+
+        let NameArena {
+            bytes,
+            spans,
+            index: (table, hasher),
+            ..
+        } = self;
+        let span = Span {
+            offset: u32::try_from(bytes.len()).expect("Name arena is larger than 4GiB"),
+            length: u32::try_from(name.to_bytes_with_nul().len())
+                .expect("Name is longer than 4GiB"),
+            references: 1,
+        };
+        bytes.extend_from_slice(name.to_bytes_with_nul());
+        let slot = u32::try_from(spans.insert(span)).expect("Too many names in the arena");
+        table.insert(hasher.hash_one(name.to_bytes()), slot, |&other| {
+            hasher.hash_one(Self::span_bytes(bytes, &spans[other as usize]))
+        });
+
+        Name(slot)
+    }
+
+    /// Look up the text of a name.
+    pub fn get(&self, name: Name) -> &CStr {
+        let span = &self.spans[name.0 as usize];
+        let start = span.offset as usize;
+        let bytes = &self.bytes[start..start + span.length as usize];
+        // names are always stored with their nul terminator, and can't contain another nul
+        unsafe { CStr::from_bytes_with_nul_unchecked(bytes) }
+    }
+
+    /// Release a name that was interned. Once it has been released as many times as it was
+    /// interned, the handle is no longer valid.
+    pub fn release(&mut self, name: Name) {
+        let span = &mut self.spans[name.0 as usize];
+        span.references -= 1;
+        if span.references > 0 {
+            return;
+        }
+
+        let (table, hasher) = &mut self.index;
+        let hash = hasher.hash_one(Self::span_bytes(&self.bytes, span));
+        if let Some(bucket) = table.find(hash, |&slot| slot == name.0) {
+            unsafe { table.erase(bucket) };
+        }
+        self.garbage += span.length as usize;
+        self.spans.remove(name.0 as usize);
+
+        if self.garbage >= MIN_GARBAGE && self.garbage * 2 >= self.bytes.len() {
+            self.compact();
+        }
+    }
+
+    /// Move all of the names that are still in use to the start of a new buffer, leaving out the
+    /// released ones.
+    fn compact(&mut self) {
+        let old = mem::take(&mut self.bytes);
+        self.bytes.reserve(old.len() - self.garbage);
+        for (_, span) in self.spans.iter_mut() {
+            let start = span.offset as usize;
+            span.offset = u32::try_from(self.bytes.len()).expect("Name arena is larger than 4GiB");
+            self.bytes
+                .extend_from_slice(&old[start..start + span.length as usize]);
+        }
+        self.garbage = 0;
+    }
+
+    /// An estimate of the memory used by the arena, in bytes.
+    pub fn memory(&self) -> usize {
+        self.bytes.capacity()
+            + self.spans.capacity() * mem::size_of::<Span>()
+            + self.index.0.buckets() * (mem::size_of::<u32>() + 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NameArena;
+    use std::ffi::CString;
+
+    #[test]
+    fn intern_names() {
+        let mut arena = NameArena::new();
+        let main = arena.intern(&CString::new("main.rs").unwrap());
+        let lib = arena.intern(&CString::new("lib.rs").unwrap());
+        assert_eq!(main, arena.intern(&CString::new("main.rs").unwrap()));
+        assert_ne!(main, lib);
+        assert_eq!(Some(lib), arena.find(b"lib.rs"));
+        assert_eq!(None, arena.find(b"mod.rs"));
+        assert_eq!(b"main.rs", arena.get(main).to_bytes());
+
+        arena.release(main);
+        assert_eq!(Some(main), arena.find(b"main.rs"));
+        arena.release(main);
+        assert_eq!(None, arena.find(b"main.rs"));
+    }
+
+    #[test]
+    fn compact_released_names() {
+        let mut arena = NameArena::new();
+        let names = (0..1000)
+            .map(|index| arena.intern(&CString::new(format!("file-{}", index)).unwrap()))
+            .collect::<Vec<_>>();
+        let length = arena.bytes.len();
+        for name in &names[..900] {
+            arena.release(*name);
+        }
+
+        assert!(arena.bytes.len() < length / 2);
+        for (index, name) in names.iter().enumerate().skip(900) {
+            let expected = format!("file-{}", index);
+            assert_eq!(expected.as_bytes(), arena.get(*name).to_bytes());
+            assert_eq!(Some(*name), arena.find(expected.as_bytes()));
+        }
+    }
+}
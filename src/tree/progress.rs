@@ -0,0 +1,70 @@
+use std::{
+    fmt::{Debug, Formatter},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// How far a crawl has got so far.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Progress {
+    /// The number of directories that have been scanned.
+    pub dirs_scanned: usize,
+    /// The number of entries found in the scanned directories, whether or not they were added to
+    /// the tree.
+    pub files_seen: usize,
+    /// The number of entries that were left out by ignore files or include globs.
+    pub ignored: usize,
+    /// The directory that was scanned most recently.
+    pub current: PathBuf,
+}
+
+/// A function to call with the progress of a crawl, each time a directory has been scanned. It's
+/// called on the thread that is building or refreshing the tree, so it should return quickly.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new(callback: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        ProgressCallback(Arc::new(callback))
+    }
+
+    pub(super) fn call(&self, progress: &Progress) {
+        (self.0)(progress)
+    }
+}
+
+impl Debug for ProgressCallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// A flag for stopping a crawl from another thread. Clones of a token share the same flag, so one
+/// clone can be given to the tree and another kept to cancel it with.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop any crawl using this token, as soon as the directories being scanned are finished. A
+    /// tree clears its token when it starts a refresh, so cancelling between refreshes is dropped
+    /// rather than stopping the next one.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Clear the flag, so that the token can stop another crawl.
+    pub(super) fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
@@ -1,129 +1,383 @@
-use crate::fs::File;
+use crate::{
+    error::Error,
+    fs::{File, FileId, FileType},
+    graph::NodeKey,
+    Result,
+};
 use std::{
-    hash::{BuildHasher, Hash, Hasher},
-    os::unix::io::{AsRawFd, RawFd},
+    collections::{BTreeMap, HashMap},
+    fmt,
+    hash::{BuildHasher, Hash},
+    mem,
+    os::unix::io::AsRawFd,
 };
 
 use ahash::RandomState;
 use hashbrown::raw::RawTable;
 use slab::Slab;
 
-/// An entry into the tree.
+/// Identifies an entry in the tree store. The slots that entries are stored in are reused once an
+/// entry is removed, so a key also holds the generation of its slot, which is bumped on each
+/// removal. Keys from before their entry was removed are stale, and don't match anything.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    index: u32,
+    generation: u32,
+}
+
+impl Key {
+    /// A key that never refers to an entry.
+    pub const DANGLING: Key = Key {
+        index: u32::MAX,
+        generation: 0,
+    };
+
+    pub fn index(self) -> usize {
+        self.index as usize
+    }
+
+    pub fn generation(self) -> u32 {
+        self.generation
+    }
+}
+
+impl NodeKey for Key {
+    fn index(self) -> usize {
+        Key::index(self)
+    }
+
+    fn generation(self) -> u32 {
+        Key::generation(self)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.index)
+    }
+}
+
+/// An entry into the tree. Only directories (and the root) hold onto an open file, other files are
+/// identified by their device and inode numbers, and reopened through their parent directory when
+/// they're needed.
 #[derive(Debug, PartialEq, Eq)]
 pub struct TreeEntry {
-    fd: File,
-    inode: u64,
+    fd: Option<File>,
+    id: FileId,
+    file_type: FileType,
 }
 
 impl TreeEntry {
     /// Create a new entry into the tree.
-    pub fn new(fd: File, inode: u64) -> Self {
-        TreeEntry { fd, inode }
+    pub fn new(fd: Option<File>, id: FileId, file_type: FileType) -> Self {
+        TreeEntry { fd, id, file_type }
+    }
+
+    pub fn fd(&self) -> Option<&File> {
+        self.fd.as_ref()
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+}
+
+/// Files that have been opened on demand, which are kept open until they're the least recently
+/// used of more than capacity files.
+struct FileCache {
+    capacity: usize,
+    files: HashMap<Key, (File, u64)>,
+    // the keys of the cached files, from least to most recently used
+    order: BTreeMap<u64, Key>,
+    next_use: u64,
+}
+
+impl FileCache {
+    fn new(capacity: usize) -> Self {
+        FileCache {
+            capacity,
+            files: HashMap::new(),
+            order: BTreeMap::new(),
+            next_use: 0,
+        }
     }
 
-    pub fn fd(&self) -> &File {
-        &self.fd
+    fn take(&mut self, key: Key) -> Option<File> {
+        let (file, last_use) = self.files.remove(&key)?;
+        self.order.remove(&last_use);
+        Some(file)
     }
 
-    pub fn inode(&self) -> u64 {
-        self.inode
+    fn insert(&mut self, key: Key, file: File) {
+        self.take(key);
+        self.files.insert(key, (file, self.next_use));
+        self.order.insert(self.next_use, key);
+        self.next_use += 1;
+        while self.files.len() > self.capacity && self.evict() {}
+    }
+
+    /// Close the least recently used file. Returns false if there weren't any files to close.
+    fn evict(&mut self) -> bool {
+        let oldest = self.order.keys().next().copied();
+        match oldest.and_then(|last_use| self.order.remove(&last_use)) {
+            Some(key) => {
+                self.files.remove(&key);
+                true
+            }
+            None => false,
+        }
     }
 }
 
 /// Indexed storage for the inside of the tree.
 pub struct TreeStore {
     storage: Slab<TreeEntry>,
+    // the current generation of each slot in storage
+    generations: Vec<u32>,
     fd_index: (RawTable<usize>, RandomState),
-    inode_index: (RawTable<usize>, RandomState),
+    id_index: (RawTable<usize>, RandomState),
+    file_cache: FileCache,
 }
 
 impl TreeStore {
-    /// Create a new tree store
-    pub fn new() -> Self {
+    /// Create a new tree store, which keeps up to cache_size files open after they've been used.
+    pub fn new(cache_size: usize) -> Self {
         let storage = Slab::new();
         let fd_index = (RawTable::new(), RandomState::new());
-        let inode_index = (RawTable::new(), RandomState::new());
+        let id_index = (RawTable::new(), RandomState::new());
 
         TreeStore {
             storage,
+            generations: Vec::new(),
             fd_index,
-            inode_index,
+            id_index,
+            file_cache: FileCache::new(cache_size),
         }
     }
 
     fn hash<T: Hash>(hash_builder: &RandomState, data: &T) -> u64 {
-        let mut hasher = hash_builder.build_hasher();
-        data.hash(&mut hasher);
-        hasher.finish()
+        hash_builder.hash_one(data)
+    }
+
+    /// The key for the entry currently stored in a slot.
+    fn key(&self, index: usize) -> Key {
+        Key {
+            // insert never fills a slot past u32::MAX, which is left for Key::DANGLING
+            index: index as u32,
+            generation: self.generations[index],
+        }
+    }
+
+    /// The slot that a key refers to, if the key isn't stale.
+    fn index(&self, key: Key) -> Option<usize> {
+        let index = key.index();
+        let is_current = self.generations.get(index) == Some(&key.generation);
+        if is_current && self.storage.contains(index) {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// The number of entries in the tree store.
+    pub fn len(&self) -> usize {
+        self.storage.len()
+    }
+
+    /// The number of files that the tree store is holding open, either for an entry or in the
+    /// cache.
+    pub fn open_files(&self) -> usize {
+        self.fd_index.0.len() + self.file_cache.files.len()
     }
 
-    /// Insert a new entry into the tree store. Returns an index that is used to uniquely identify
-    /// that entry later. This function will panic if either the fd of the entry, or the inode, has
-    /// been used before in a different entry. Reinserting an entry that is identical in all fields
-    /// to an entry that has previously been inserted will return the existing key.
-    pub fn insert(&mut self, entry: TreeEntry) -> usize {
+    /// An estimate of the memory used by the tree store, in bytes. This doesn't include the files
+    /// that are held open.
+    pub fn memory(&self) -> usize {
+        let entry_size = mem::size_of::<TreeEntry>() + mem::size_of::<usize>();
+        let index_size = mem::size_of::<usize>() + 1;
+        self.storage.capacity() * entry_size
+            + self.generations.capacity() * mem::size_of::<u32>()
+            + (self.fd_index.0.buckets() + self.id_index.0.buckets()) * index_size
+    }
+
+    /// Insert a new entry into the tree store. Returns a key that is used to uniquely identify that
+    /// entry later. Fails if either the fd of the entry, or the file id, is already used by a
+    /// different entry. Reinserting an entry that is identical in all fields to an entry that has
+    /// previously been inserted will return the existing key. Fails if there's no slot left that a
+    /// key can refer to.
+    pub fn insert(&mut self, entry: TreeEntry) -> Result<Key> {
         let TreeStore {
             storage,
+            generations,
             fd_index: (fd_table, fd_hasher),
-            inode_index: (inode_table, inode_hasher),
+            id_index: (id_table, id_hasher),
+            ..
         } = self;
-        let fd_hash = Self::hash(fd_hasher, &entry.fd.as_raw_fd());
-        let inode_hash = Self::hash(inode_hasher, &entry.inode);
+        let fd_hash = entry
+            .fd
+            .as_ref()
+            .map(|fd| Self::hash(fd_hasher, &fd.as_raw_fd()));
+        let id_hash = Self::hash(id_hasher, &entry.id);
 
-        let fd_bucket = fd_table
-            .find(fd_hash, |&index| storage[index] == entry)
-            .map(|bucket| unsafe { bucket.read() });
-        let inode_bucket = inode_table
-            .find(inode_hash, |&index| storage[index] == entry)
+        let fd_bucket = fd_hash.and_then(|fd_hash| {
+            fd_table
+                .find(fd_hash, |&index| storage[index].fd == entry.fd)
+                .map(|bucket| unsafe { bucket.read() })
+        });
+        let id_bucket = id_table
+            .find(id_hash, |&index| storage[index].id == entry.id)
             .map(|bucket| unsafe { bucket.read() });
 
-        match (fd_bucket, inode_bucket) {
-            (Some(fd_bucket), Some(inode_bucket)) if fd_bucket == inode_bucket => fd_bucket,
-            (None, None) => {
-                let key = storage.insert(entry);
-                fd_table.insert(fd_hash, key, |&key2| {
-                    Self::hash(fd_hasher, &storage[key2].fd.as_raw_fd())
-                });
-                inode_table.insert(inode_hash, key, |&key2| {
-                    Self::hash(inode_hasher, &storage[key2].inode)
+        let index = match (fd_hash, fd_bucket, id_bucket) {
+            (Some(_), Some(fd_bucket), Some(id_bucket))
+                if fd_bucket == id_bucket && storage[id_bucket] == entry =>
+            {
+                fd_bucket
+            }
+            (None, None, Some(id_bucket)) if storage[id_bucket] == entry => id_bucket,
+            (_, None, None) => {
+                let len = storage.len();
+                let slot = storage.vacant_entry();
+                let key = slot.key();
+                if key >= u32::MAX as usize {
+                    return Err(Error::StoreFull(len));
+                }
+                slot.insert(entry);
+                if key >= generations.len() {
+                    generations.resize(key + 1, 0);
+                }
+                if let Some(fd_hash) = fd_hash {
+                    fd_table.insert(fd_hash, key, |&key2| {
+                        let fd = storage[key2].fd.as_ref().unwrap();
+                        Self::hash(fd_hasher, &fd.as_raw_fd())
+                    });
+                }
+                id_table.insert(id_hash, key, |&key2| {
+                    Self::hash(id_hasher, &storage[key2].id)
                 });
                 key
             }
-            _ => unreachable!("Attempt to create entry that shares data with existing entry"),
-        }
+            _ => return Err(Error::ConflictingEntry(entry.id.dev, entry.id.inode)),
+        };
+        Ok(self.key(index))
     }
 
-    /// Lookup a tree entry by the key that it was stored with originally.
-    pub fn key_to_entry(&self, key: usize) -> Option<&TreeEntry> {
-        self.storage.get(key)
+    /// Lookup a tree entry by the key that it was stored with originally. Returns None if the key
+    /// is stale.
+    pub fn key_to_entry(&self, key: Key) -> Option<&TreeEntry> {
+        self.index(key).map(|index| &self.storage[index])
     }
 
-    /// Lookup a tree key by the fd of the entry that it was stored with originally.
-    pub fn fd_to_key(&self, fd: RawFd) -> Option<usize> {
-        let (table, hasher) = &self.fd_index;
-        let hash = Self::hash(hasher, &fd);
+    /// Lookup a tree key by the file id of the entry that it was stored with originally.
+    pub fn id_to_key(&self, id: FileId) -> Option<Key> {
+        let (table, hasher) = &self.id_index;
+        let hash = Self::hash(hasher, &id);
         table
-            .find(hash, |&index| self.storage[index].fd.as_raw_fd() == fd)
-            .map(|bucket| unsafe { bucket.read() })
+            .find(hash, |&index| self.storage[index].id == id)
+            .map(|bucket| self.key(unsafe { bucket.read() }))
     }
 
-    /// Lookup a tree entry by its fd.
-    pub fn fd_to_entry(&self, fd: RawFd) -> Option<&TreeEntry> {
-        self.fd_to_key(fd).and_then(|key| self.key_to_entry(key))
+    /// Take the file that was opened for an entry out of the cache, if it's still there.
+    pub fn take_file(&mut self, key: Key) -> Option<File> {
+        self.file_cache.take(key)
     }
 
-    /// Lookup a tree key by the inode of the entry that it was stored with originally.
-    pub fn inode_to_key(&self, inode: u64) -> Option<usize> {
-        let (table, hasher) = &self.inode_index;
-        let hash = Self::hash(hasher, &inode);
-        table
-            .find(hash, |&index| self.storage[index].inode == inode)
-            .map(|bucket| unsafe { bucket.read() })
+    /// Put a file that was opened for an entry into the cache, closing the least recently used
+    /// files if the cache is full.
+    pub fn cache_file(&mut self, key: Key, file: File) {
+        self.file_cache.insert(key, file);
+    }
+
+    /// Close the least recently used file in the cache, to make room for another open file.
+    /// Returns false if the cache was already empty.
+    pub fn release_file(&mut self) -> bool {
+        self.file_cache.evict()
+    }
+
+    /// Remove an entry from the tree store, returning it if it was present. The key becomes
+    /// stale, and its slot may be reused by entries inserted later.
+    pub fn remove(&mut self, key: Key) -> Option<TreeEntry> {
+        let index = self.index(key)?;
+        let TreeStore {
+            storage,
+            generations,
+            fd_index: (fd_table, fd_hasher),
+            id_index: (id_table, id_hasher),
+            file_cache,
+        } = self;
+        let entry = &storage[index];
+
+        if let Some(fd) = &entry.fd {
+            let fd_hash = Self::hash(fd_hasher, &fd.as_raw_fd());
+            if let Some(bucket) = fd_table.find(fd_hash, |&other| other == index) {
+                unsafe { fd_table.erase(bucket) };
+            }
+        }
+        let id_hash = Self::hash(id_hasher, &entry.id);
+        if let Some(bucket) = id_table.find(id_hash, |&other| other == index) {
+            unsafe { id_table.erase(bucket) };
+        }
+        file_cache.take(key);
+        generations[index] = generations[index].wrapping_add(1);
+
+        Some(storage.remove(index))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileCache, Key, TreeEntry, TreeStore};
+    use crate::{
+        error::Error,
+        fs::{File, FileId, FileType},
+    };
+    use std::ffi::CString;
+
+    fn key(index: u32, generation: u32) -> Key {
+        Key { index, generation }
     }
 
-    /// Lookup a tree entry by its inode.
-    pub fn inode_to_entry(&self, inode: u64) -> Option<&TreeEntry> {
-        self.inode_to_key(inode)
-            .and_then(|key| self.key_to_entry(key))
+    #[test]
+    fn file_cache_evicts_least_recently_used() {
+        let open = || File::open(&CString::new(env!("CARGO_MANIFEST_DIR")).unwrap()).unwrap();
+        let mut cache = FileCache::new(2);
+        cache.insert(key(1, 0), open());
+        cache.insert(key(2, 0), open());
+        let file = cache.take(key(1, 0)).unwrap();
+        cache.insert(key(1, 0), file);
+        cache.insert(key(3, 0), open());
+
+        assert!(cache.take(key(2, 0)).is_none());
+        assert!(cache.take(key(1, 0)).is_some());
+        assert!(cache.take(key(3, 0)).is_some());
+
+        let mut cache = FileCache::new(0);
+        cache.insert(key(1, 0), open());
+        assert!(cache.files.is_empty());
+    }
+
+    #[test]
+    fn stale_keys() {
+        let id = |inode| FileId { dev: 1, inode };
+        let entry = |inode| TreeEntry::new(None, id(inode), FileType::Regular);
+        let mut store = TreeStore::new(0);
+
+        let first = store.insert(entry(1)).unwrap();
+        assert_eq!(first, store.insert(entry(1)).unwrap());
+        assert!(store.remove(first).is_some());
+        assert!(store.remove(first).is_none());
+
+        // the slot is reused, but the old key doesn't refer to the new entry
+        let second = store.insert(entry(2)).unwrap();
+        assert_eq!(first.index(), second.index());
+        assert!(store.key_to_entry(first).is_none());
+        assert!(store.key_to_entry(second).is_some());
+        assert_eq!(Some(second), store.id_to_key(id(2)));
+
+        let conflict = TreeEntry::new(None, id(2), FileType::Directory);
+        assert!(matches!(
+            store.insert(conflict),
+            Err(Error::ConflictingEntry(1, 2))
+        ));
     }
 }